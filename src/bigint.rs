@@ -0,0 +1,657 @@
+use crate::memory::{Marker, Trace};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+/// An integer of any size, stored as a sign and a magnitude in base 2^32 digits,
+/// least significant first.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    // Has no leading zero digits, so zero is empty and never negative
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut digits: Vec<u32>) -> BigInt {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    /// Parses decimal digits with an optional leading '-'.
+    pub fn parse(text: &str) -> Option<BigInt> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+
+        if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut digits = Vec::new();
+        // Nine decimal digits at a time always fit in a u32
+        for chunk in text.as_bytes().chunks(9) {
+            let chunk = std::str::from_utf8(chunk).unwrap();
+            mul_small(&mut digits, 10u32.pow(chunk.len() as u32));
+            add_small(&mut digits, chunk.parse().unwrap());
+        }
+
+        Some(BigInt::new(negative, digits))
+    }
+
+    /// The integer equal to `n`, if `n` is finite and has no fractional part.
+    pub fn from_f64(n: f64) -> Option<BigInt> {
+        if !n.is_finite() || n.fract() != 0.0 {
+            return None;
+        }
+
+        let bits = n.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i32;
+        if exponent == 0 {
+            // Subnormal, so the only integer it can be is zero
+            return Some(BigInt::from(0));
+        }
+
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let shift = exponent - 1075;
+        let magnitude = if shift >= 0 {
+            shift_left(&[mantissa as u32, (mantissa >> 32) as u32], shift as usize)
+        } else {
+            let mantissa = mantissa >> -shift;
+            vec![mantissa as u32, (mantissa >> 32) as u32]
+        };
+
+        Some(BigInt::new(n < 0.0, magnitude))
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0i128, |acc, &digit| (acc << 32) | digit as i128);
+        let value = if self.negative { -magnitude } else { magnitude };
+
+        i64::try_from(value).ok()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &digit| acc * 4294967296.0 + digit as f64);
+
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// The number of bits in the magnitude, which is 0 for zero.
+    pub fn bit_length(&self) -> u64 {
+        match self.digits.last() {
+            Some(top) => self.digits.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    /// The quotient rounded toward zero and the remainder, which takes the sign of
+    /// `self` like Rust's `/` and `%`. None when dividing by zero.
+    pub fn divmod(&self, divisor: &BigInt) -> Option<(BigInt, BigInt)> {
+        if divisor.digits.is_empty() {
+            return None;
+        }
+
+        let (quotient, remainder) = divmod_magnitude(&self.digits, &divisor.digits);
+
+        Some((
+            BigInt::new(self.negative != divisor.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        ))
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::from(1);
+        let mut base = self.clone();
+
+        // Square-and-multiply over the exponent's bits
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+
+        result
+    }
+
+    // The value's two's complement representation, sign-extended to `length` digits
+    fn twos_complement(&self, length: usize) -> Vec<u32> {
+        let mut digits = self.digits.clone();
+        digits.resize(length, 0);
+
+        if self.negative {
+            for digit in digits.iter_mut() {
+                *digit = !*digit;
+            }
+            add_small(&mut digits, 1);
+        }
+
+        digits
+    }
+
+    fn from_twos_complement(mut digits: Vec<u32>) -> BigInt {
+        let negative = digits.last().is_some_and(|digit| digit >> 31 == 1);
+
+        if negative {
+            for digit in digits.iter_mut() {
+                *digit = !*digit;
+            }
+            add_small(&mut digits, 1);
+        }
+
+        BigInt::new(negative, digits)
+    }
+
+    fn bitwise(&self, other: &BigInt, op: fn(u32, u32) -> u32) -> BigInt {
+        // One extra digit so the sign bit is never lost
+        let length = self.digits.len().max(other.digits.len()) + 1;
+        let digits = self
+            .twos_complement(length)
+            .iter()
+            .zip(other.twos_complement(length))
+            .map(|(&a, b)| op(a, b))
+            .collect();
+
+        BigInt::from_twos_complement(digits)
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> BigInt {
+        let magnitude = n.unsigned_abs();
+        BigInt::new(n < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+impl Trace for BigInt {
+    fn trace(&self, _marker: &mut Marker) {}
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitude(&self.digits, &other.digits),
+            (true, true) => compare_magnitude(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::ops::Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+}
+
+impl std::ops::Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitude(&self.digits, &other.digits));
+        }
+
+        // Opposite signs, so the result takes the sign of the larger magnitude
+        match compare_magnitude(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::new(other.negative, sub_magnitude(&other.digits, &self.digits))
+            }
+            _ => BigInt::new(self.negative, sub_magnitude(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl std::ops::Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl std::ops::Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut product = vec![0u32; self.digits.len() + other.digits.len()];
+
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let sum = product[i + j] as u64 + a as u64 * b as u64 + carry;
+                product[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            product[i + other.digits.len()] = carry as u32;
+        }
+
+        BigInt::new(self.negative != other.negative, product)
+    }
+}
+
+impl std::ops::Div for &BigInt {
+    type Output = BigInt;
+
+    fn div(self, other: &BigInt) -> BigInt {
+        self.divmod(other).expect("attempt to divide by zero").0
+    }
+}
+
+impl std::ops::Rem for &BigInt {
+    type Output = BigInt;
+
+    fn rem(self, other: &BigInt) -> BigInt {
+        self.divmod(other).expect("attempt to divide by zero").1
+    }
+}
+
+impl std::ops::BitAnd for &BigInt {
+    type Output = BigInt;
+
+    fn bitand(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+}
+
+impl std::ops::BitOr for &BigInt {
+    type Output = BigInt;
+
+    fn bitor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+}
+
+impl std::ops::BitXor for &BigInt {
+    type Output = BigInt;
+
+    fn bitxor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+}
+
+impl std::ops::Not for &BigInt {
+    type Output = BigInt;
+
+    // Two's complement, so !x is -x - 1
+    fn not(self) -> BigInt {
+        &-self - &BigInt::from(1)
+    }
+}
+
+impl std::ops::Shl<usize> for &BigInt {
+    type Output = BigInt;
+
+    fn shl(self, bits: usize) -> BigInt {
+        BigInt::new(self.negative, shift_left(&self.digits, bits))
+    }
+}
+
+impl std::ops::Shr<usize> for &BigInt {
+    type Output = BigInt;
+
+    // Rounds toward negative infinity, like shifting a two's complement number
+    fn shr(self, bits: usize) -> BigInt {
+        if !self.negative {
+            return BigInt::new(false, shift_right(&self.digits, bits));
+        }
+
+        // -x >> n is -(((x - 1) >> n) + 1)
+        let magnitude = sub_magnitude(&self.digits, &[1]);
+        let mut shifted = shift_right(&magnitude, bits);
+        add_small(&mut shifted, 1);
+        BigInt::new(true, shifted)
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.digits.is_empty() {
+            return write!(f, "0");
+        }
+
+        // Peel off nine decimal digits at a time, least significant first
+        let mut magnitude = self.digits.clone();
+        let mut chunks = Vec::new();
+        while !magnitude.is_empty() {
+            chunks.push(div_small(&mut magnitude, 1_000_000_000));
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn compare_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+
+    for i in 0..a.len().max(b.len()) {
+        let digit = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(digit as u32);
+        carry = digit >> 32;
+    }
+    sum.push(carry as u32);
+
+    sum
+}
+
+// Requires a >= b
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+
+    for (i, &digit) in a.iter().enumerate() {
+        let mut digit = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if digit < 0 {
+            digit += 1 << 32;
+            borrow = 1;
+        }
+        difference.push(digit as u32);
+    }
+
+    difference
+}
+
+fn shift_left(digits: &[u32], bits: usize) -> Vec<u32> {
+    let mut shifted = vec![0u32; bits / 32];
+    let bits = bits % 32;
+    let mut carry = 0u32;
+
+    for &digit in digits {
+        shifted.push(if bits == 0 {
+            digit
+        } else {
+            (digit << bits) | carry
+        });
+        carry = if bits == 0 { 0 } else { digit >> (32 - bits) };
+    }
+    shifted.push(carry);
+
+    shifted
+}
+
+fn shift_right(digits: &[u32], bits: usize) -> Vec<u32> {
+    let skipped = bits / 32;
+    if skipped >= digits.len() {
+        return Vec::new();
+    }
+
+    let bits = bits % 32;
+    let digits = &digits[skipped..];
+    let mut shifted = Vec::with_capacity(digits.len());
+
+    for (i, &digit) in digits.iter().enumerate() {
+        let next = if bits == 0 {
+            0
+        } else {
+            digits.get(i + 1).map_or(0, |&next| next << (32 - bits))
+        };
+        shifted.push((digit >> bits) | next);
+    }
+
+    shifted
+}
+
+// Schoolbook long division, one bit at a time
+fn divmod_magnitude(dividend: &[u32], divisor: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [divisor] = divisor {
+        let mut quotient = dividend.to_vec();
+        let remainder = div_small(&mut quotient, *divisor);
+        return (quotient, vec![remainder]);
+    }
+
+    let mut quotient = vec![0u32; dividend.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+
+    for bit in (0..dividend.len() * 32).rev() {
+        remainder = shift_left(&remainder, 1);
+        remainder[0] |= (dividend[bit / 32] >> (bit % 32)) & 1;
+        while remainder.last() == Some(&0) {
+            remainder.pop();
+        }
+
+        if compare_magnitude(&remainder, divisor) != Ordering::Less {
+            remainder = sub_magnitude(&remainder, divisor);
+            while remainder.last() == Some(&0) {
+                remainder.pop();
+            }
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+
+    (quotient, remainder)
+}
+
+// Divides in place, returning the remainder. Leading zeros are trimmed.
+fn div_small(digits: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+
+    for digit in digits.iter_mut().rev() {
+        let current = (remainder << 32) | *digit as u64;
+        *digit = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+
+    remainder as u32
+}
+
+fn mul_small(digits: &mut Vec<u32>, factor: u32) {
+    let mut carry = 0u64;
+
+    for digit in digits.iter_mut() {
+        let product = *digit as u64 * factor as u64 + carry;
+        *digit = product as u32;
+        carry = product >> 32;
+    }
+
+    if carry > 0 {
+        digits.push(carry as u32);
+    }
+}
+
+fn add_small(digits: &mut Vec<u32>, addend: u32) {
+    let mut carry = addend as u64;
+
+    for digit in digits.iter_mut() {
+        if carry == 0 {
+            return;
+        }
+        let sum = *digit as u64 + carry;
+        *digit = sum as u32;
+        carry = sum >> 32;
+    }
+
+    if carry > 0 {
+        digits.push(carry as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> BigInt {
+        BigInt::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for text in [
+            "0",
+            "7",
+            "-42",
+            "18446744073709551616",
+            "-123456789012345678901234567890",
+        ] {
+            assert_eq!(big(text).to_string(), text);
+        }
+
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("000123").to_string(), "123");
+        assert_eq!(BigInt::parse("12a"), None);
+        assert_eq!(BigInt::parse("-"), None);
+    }
+
+    #[test]
+    fn i64_round_trip() {
+        for n in [0, 1, -1, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from(n).to_i64(), Some(n));
+        }
+
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            (&a * &b).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!((&a - &a).to_string(), "0");
+    }
+
+    #[test]
+    fn division_truncates() {
+        let (quotient, remainder) = big("-100000000000000000000")
+            .divmod(&big("30000000000"))
+            .unwrap();
+
+        assert_eq!(quotient.to_string(), "-3333333333");
+        assert_eq!(remainder.to_string(), "-10000000000");
+
+        let (quotient, remainder) = big("1000000000000000000000").divmod(&big("7")).unwrap();
+
+        assert_eq!(quotient.to_string(), "142857142857142857142");
+        assert_eq!(remainder.to_string(), "6");
+        assert_eq!(big("1").divmod(&big("0")), None);
+    }
+
+    #[test]
+    fn ordering() {
+        assert!(big("-18446744073709551616") < big("-1"));
+        assert!(big("-1") < big("0"));
+        assert!(big("18446744073709551615") < big("18446744073709551616"));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(
+            BigInt::from_f64(2f64.powi(70)).unwrap().to_string(),
+            "1180591620717411303424"
+        );
+        assert_eq!(BigInt::from_f64(-3.0).unwrap().to_string(), "-3");
+        assert_eq!(BigInt::from_f64(0.5), None);
+        assert_eq!(BigInt::from_f64(f64::INFINITY), None);
+        assert_eq!(big("1180591620717411303424").to_f64(), 2f64.powi(70));
+    }
+
+    #[test]
+    fn powers() {
+        assert_eq!(
+            big("2").pow(100).to_string(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(big("-3").pow(3).to_string(), "-27");
+        assert_eq!(big("12345").pow(0).to_string(), "1");
+    }
+
+    #[test]
+    fn bitwise() {
+        let a = big("-18446744073709551617");
+        let b = big("340282366920938463463374607431768211455");
+
+        assert_eq!(
+            (&a & &b).to_string(),
+            "340282366920938463444927863358058659839"
+        );
+        assert_eq!((&a | &b).to_string(), "-1");
+        assert_eq!(
+            (&a ^ &b).to_string(),
+            "-340282366920938463444927863358058659840"
+        );
+        assert_eq!((!&a).to_string(), "18446744073709551616");
+        assert_eq!((&BigInt::from(-6) & &BigInt::from(3)).to_string(), "2");
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!((&big("3") << 65).to_string(), "110680464442257309696");
+        assert_eq!((&big("110680464442257309696") >> 65).to_string(), "3");
+        assert_eq!((&big("-110680464442257309697") >> 65).to_string(), "-4");
+        assert_eq!((&big("-1") >> 200).to_string(), "-1");
+        assert_eq!((&big("12345") >> 200).to_string(), "0");
+    }
+
+    #[test]
+    fn bit_lengths() {
+        assert_eq!(big("0").bit_length(), 0);
+        assert_eq!(big("-1").bit_length(), 1);
+        assert_eq!(big("4294967295").bit_length(), 32);
+        assert_eq!(big("-4294967296").bit_length(), 33);
+    }
+}
@@ -1,44 +1,57 @@
-#[derive(Clone, PartialEq, Debug)]
+use crate::bigint::BigInt;
+use crate::memory::Gc;
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, List, Map, Module, Native};
+use std::hash::{Hash, Hasher};
+
+#[derive(Clone, Copy, Debug)]
 pub enum Value {
+    Int(i64),
+    // Only holds integers outside the range of `Int`
+    BigInt(Gc<BigInt>),
     Float(f64),
     Bool(bool),
-    Str(String),
+    Str(Gc<String>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<Class>),
+    Instance(Gc<Instance>),
+    BoundMethod(Gc<BoundMethod>),
+    Native(Gc<Native>),
+    List(Gc<List>),
+    Map(Gc<Map>),
+    Module(Gc<Module>),
     Nil,
 }
 
 impl Value {
-    pub fn is_float(&self) -> bool {
-        if let Value::Float(_) = *self {
-            true
-        } else {
-            false
-        }
+    pub fn is_number(&self) -> bool {
+        matches!(*self, Value::Int(_) | Value::BigInt(_) | Value::Float(_))
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(*self, Value::Int(_) | Value::BigInt(_))
     }
 
+    #[allow(dead_code)]
     pub fn is_bool(&self) -> bool {
-        if let Value::Bool(_) = *self {
-            true
-        } else {
-            false
-        }
+        matches!(*self, Value::Bool(_))
     }
 
     pub fn is_str(&self) -> bool {
-        if let Value::Str(_) = *self {
-            true
-        } else {
-            false
-        }
+        matches!(*self, Value::Str(_))
     }
 
+    /// The value as a float, converting integers.
     pub fn as_float(&self) -> f64 {
-        if let Value::Float(f) = *self {
-            f
-        } else {
-            panic!("Value ({}) is not a float", *self);
+        match *self {
+            Value::Int(n) => n as f64,
+            Value::BigInt(n) => n.to_f64(),
+            Value::Float(f) => f,
+            _ => panic!("Value ({}) is not a number", *self),
         }
     }
 
+    #[allow(dead_code)]
     pub fn as_bool(&self) -> bool {
         if let Value::Bool(b) = *self {
             b
@@ -47,41 +60,147 @@ impl Value {
         }
     }
 
+    pub fn as_bigint(&self) -> BigInt {
+        match *self {
+            Value::Int(n) => BigInt::from(n),
+            Value::BigInt(n) => (*n).clone(),
+            _ => panic!("Value ({}) is not an integer", *self),
+        }
+    }
+
     pub fn as_str(&self) -> &str {
         if let Value::Str(s) = self {
-            &*s
+            s
         } else {
             panic!("Value ({}) is not a string", *self);
         }
     }
 
+    #[allow(dead_code)]
     pub fn is_nil(&self) -> bool {
-        if let Value::Nil = *self {
-            true
-        } else {
-            false
+        matches!(*self, Value::Nil)
+    }
+
+    /// Whether the value can be used as a map key. Objects other than strings are
+    /// excluded, and so is NaN since it never equals itself.
+    pub fn is_hashable(&self) -> bool {
+        match *self {
+            Value::Float(n) => !n.is_nan(),
+            Value::Int(_) | Value::BigInt(_) | Value::Bool(_) | Value::Str(_) | Value::Nil => true,
+            _ => false,
         }
     }
 
     pub fn is_falsey(&self) -> bool {
-        match *self {
-            Value::Nil | Value::Bool(false) => true,
+        matches!(*self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => {
+                float_as_int(*b) == Some(*a)
+            }
+            (Value::BigInt(a), Value::BigInt(b)) => **a == **b,
+            (Value::BigInt(a), Value::Float(b)) | (Value::Float(b), Value::BigInt(a)) => {
+                BigInt::from_f64(*b).as_ref() == Some(&**a)
+            }
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            // Strings are interned, so equal contents means the same object
+            (Value::Str(a), Value::Str(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Function(a), Value::Function(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Closure(a), Value::Closure(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Class(a), Value::Class(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Instance(a), Value::Instance(b)) => Gc::ptr_eq(*a, *b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Native(a), Value::Native(b)) => Gc::ptr_eq(*a, *b),
+            (Value::List(a), Value::List(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Map(a), Value::Map(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Module(a), Value::Module(b)) => Gc::ptr_eq(*a, *b),
+            (Value::Nil, Value::Nil) => true,
             _ => false,
         }
     }
 }
 
+// Only sound for values where `is_hashable` holds, which the VM checks before
+// using a value as a map key
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Integers and integral floats are equal, so they must hash the same
+        let integer_tag = std::mem::discriminant(&Value::Int(0));
+        match *self {
+            Value::Int(n) => return (integer_tag, n).hash(state),
+            Value::BigInt(n) => return (integer_tag, &*n).hash(state),
+            Value::Float(n) => {
+                if let Some(n) = float_as_int(n) {
+                    return (integer_tag, n).hash(state);
+                }
+                if let Some(n) = BigInt::from_f64(n) {
+                    return (integer_tag, n).hash(state);
+                }
+            }
+            _ => (),
+        }
+
+        std::mem::discriminant(self).hash(state);
+        match *self {
+            Value::Int(_) | Value::BigInt(_) => unreachable!(),
+            Value::Float(n) => n.to_bits().hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Function(function) => function.hash(state),
+            Value::Closure(closure) => closure.hash(state),
+            Value::Class(class) => class.hash(state),
+            Value::Instance(instance) => instance.hash(state),
+            Value::BoundMethod(bound) => bound.hash(state),
+            Value::Native(native) => native.hash(state),
+            Value::List(list) => list.hash(state),
+            Value::Map(map) => map.hash(state),
+            Value::Module(module) => module.hash(state),
+            Value::Nil => (),
+        }
+    }
+}
+
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::BigInt(n) => write!(f, "{}", n),
             Value::Float(n) => write!(f, "{}", n),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Str(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Closure(closure) => write!(f, "{}", closure),
+            Value::Class(class) => write!(f, "{}", class),
+            Value::Instance(instance) => write!(f, "{}", instance),
+            Value::BoundMethod(bound) => write!(f, "{}", bound),
+            Value::Native(native) => write!(f, "{}", native),
+            Value::List(list) => write!(f, "{}", list),
+            Value::Map(map) => write!(f, "{}", map),
+            Value::Module(module) => write!(f, "<module {}>", module),
             Value::Nil => write!(f, "nil"),
         }
     }
 }
 
+/// The integer equal to `n`, if there is one.
+pub fn float_as_int(n: f64) -> Option<i64> {
+    // i64::MAX isn't representable as a float; the bound rounds up to 2^63
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+#[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum OpCode {
     Add,
@@ -97,6 +216,50 @@ pub enum OpCode {
     Equal,
     Greater,
     Less,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Call,
+    Invoke,
+    SuperInvoke,
+    Closure,
+    CloseUpvalue,
+    Class,
+    Inherit,
+    Method,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    BuildMap,
+    Contains,
+    Stringify,
+    PushHandler,
+    PopHandler,
+    Throw,
+    Import,
+    ImportFrom,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    Copy,
+    Bury,
     Return,
 }
 
@@ -126,6 +289,8 @@ impl Chunk {
         self.constants.len() - 1
     }
 
+    // A debugging aid for the compiler; nothing calls it in normal runs
+    #[allow(dead_code)]
     pub fn disassemble(&self, description: &str) {
         println!("=== {} ===", description);
         let mut offset: usize = 0;
@@ -161,6 +326,50 @@ impl Chunk {
             OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
             OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
             OpCode::Less => self.simple_instruction("OP_LESS", offset),
+            OpCode::Print => self.simple_instruction("OP_PRINT", offset),
+            OpCode::Pop => self.simple_instruction("OP_POP", offset),
+            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
+            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
+            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
+            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
+            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
+            OpCode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset),
+            OpCode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset),
+            OpCode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", offset),
+            OpCode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", offset),
+            OpCode::GetSuper => self.constant_instruction("OP_GET_SUPER", offset),
+            OpCode::Call => self.byte_instruction("OP_CALL", offset),
+            OpCode::Invoke => self.invoke_instruction("OP_INVOKE", offset),
+            OpCode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset),
+            OpCode::Closure => self.closure_instruction("OP_CLOSURE", offset),
+            OpCode::CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", offset),
+            OpCode::Class => self.constant_instruction("OP_CLASS", offset),
+            OpCode::Inherit => self.simple_instruction("OP_INHERIT", offset),
+            OpCode::Method => self.constant_instruction("OP_METHOD", offset),
+            OpCode::Jump => self.jump_instruction("OP_JUMP", 1, offset),
+            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
+            OpCode::Loop => self.jump_instruction("OP_LOOP", -1, offset),
+            OpCode::BuildList => self.byte_instruction("OP_BUILD_LIST", offset),
+            OpCode::IndexGet => self.simple_instruction("OP_INDEX_GET", offset),
+            OpCode::IndexSet => self.simple_instruction("OP_INDEX_SET", offset),
+            OpCode::BuildMap => self.byte_instruction("OP_BUILD_MAP", offset),
+            OpCode::Contains => self.simple_instruction("OP_CONTAINS", offset),
+            OpCode::Stringify => self.simple_instruction("OP_STRINGIFY", offset),
+            OpCode::PushHandler => self.jump_instruction("OP_PUSH_HANDLER", 1, offset),
+            OpCode::PopHandler => self.simple_instruction("OP_POP_HANDLER", offset),
+            OpCode::Throw => self.simple_instruction("OP_THROW", offset),
+            OpCode::Import => self.constant_instruction("OP_IMPORT", offset),
+            OpCode::ImportFrom => self.constant_instruction("OP_IMPORT_FROM", offset),
+            OpCode::Modulo => self.simple_instruction("OP_MODULO", offset),
+            OpCode::Power => self.simple_instruction("OP_POWER", offset),
+            OpCode::BitAnd => self.simple_instruction("OP_BIT_AND", offset),
+            OpCode::BitOr => self.simple_instruction("OP_BIT_OR", offset),
+            OpCode::BitXor => self.simple_instruction("OP_BIT_XOR", offset),
+            OpCode::BitNot => self.simple_instruction("OP_BIT_NOT", offset),
+            OpCode::ShiftLeft => self.simple_instruction("OP_SHIFT_LEFT", offset),
+            OpCode::ShiftRight => self.simple_instruction("OP_SHIFT_RIGHT", offset),
+            OpCode::Copy => self.byte_instruction("OP_COPY", offset),
+            OpCode::Bury => self.byte_instruction("OP_BURY", offset),
         }
     }
 
@@ -169,6 +378,65 @@ impl Chunk {
         offset + 1
     }
 
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{0} {slot:>0width$}", name, slot = slot, width = 4);
+        offset + 2
+    }
+
+    fn jump_instruction(&self, name: &str, sign: isize, offset: usize) -> usize {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        let target = offset as isize + 3 + sign * jump as isize;
+        println!(
+            "{0} {offset:>0width$} -> {1}",
+            name,
+            target,
+            offset = offset,
+            width = 4
+        );
+        offset + 3
+    }
+
+    fn closure_instruction(&self, name: &str, offset: usize) -> usize {
+        let mut offset = self.constant_instruction(name, offset);
+
+        let upvalue_count = match &self.constants[self.code[offset - 1] as usize] {
+            Value::Function(function) => function.upvalue_count,
+            _ => 0,
+        };
+
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset] == 1;
+            let index = self.code[offset + 1];
+            println!(
+                "{offset:>0width$}    |   {0} {1}",
+                if is_local { "local" } else { "upvalue" },
+                index,
+                offset = offset,
+                width = 4,
+            );
+            offset += 2;
+        }
+
+        offset
+    }
+
+    fn invoke_instruction(&self, name: &str, offset: usize) -> usize {
+        let index = self.code[offset + 1] as usize;
+        let argument_count = self.code[offset + 2];
+
+        println!(
+            "{0} ({1} args) {index:>0width$} '{2}'",
+            name,
+            argument_count,
+            self.constants[index],
+            index = index,
+            width = 4,
+        );
+
+        offset + 3
+    }
+
     fn constant_instruction(&self, name: &str, offset: usize) -> usize {
         let index = self.code[offset + 1] as usize;
 
@@ -1,34 +1,171 @@
+use crate::bigint::BigInt;
 use crate::bytecode::{Chunk, OpCode, Value};
+use crate::memory::{Gc, Heap};
+use crate::object::{Function, Module};
 use crate::scanner::{Scanner, Token, TokenKind};
+use crate::vm::InterpretError;
 
 pub struct Compiler<'a> {
     scanner: Scanner<'a>,
+    // Constants are allocated here; nothing is collected while compiling
+    heap: &'a mut Heap,
+    // The module whose globals the compiled code uses
+    module: Gc<Module>,
     current: Token<'a>,
     previous: Option<Token<'a>>,
-    chunk: Option<Chunk>,
+    // One entry per function being compiled, innermost last
+    compilers: Vec<FunctionCompiler<'a>>,
+    // One entry per class body being compiled, innermost last
+    classes: Vec<ClassCompiler>,
+    // The variable, field or index last read in the expression being parsed
+    target: Option<Target>,
     had_error: bool,
     panic_mode: bool,
+    // Set for an imported module, whose errors would otherwise be mistaken for the importer's
+    name_module: bool,
 }
 
+struct FunctionCompiler<'a> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Enclosing loops of the statement being compiled, innermost last
+    loops: Vec<Loop>,
+    // Exception handlers installed at this point in the function
+    handler_depth: usize,
+    // Enclosing try statements of the statement being compiled, innermost last
+    tries: Vec<Try>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(kind: FunctionKind, name: Option<String>, module: Gc<Module>) -> FunctionCompiler<'a> {
+        // The first slot holds the function being called, or the receiver for methods
+        let callee = Local {
+            name: match kind {
+                FunctionKind::Method | FunctionKind::Initializer => "this",
+                FunctionKind::Function | FunctionKind::Script => "",
+            },
+            depth: Some(0),
+            is_captured: false,
+        };
+
+        FunctionCompiler {
+            function: Function::new(name, module),
+            kind,
+            locals: vec![callee],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            handler_depth: 0,
+            tries: Vec::new(),
+        }
+    }
+}
+
+struct Loop {
+    // Where `continue` jumps to
+    start: usize,
+    // Scope depth outside the body; deeper locals are discarded when jumping out
+    scope_depth: usize,
+    // `break` jumps to patch once the end of the loop is known
+    breaks: Vec<usize>,
+    // Handlers installed inside the body are removed when jumping out
+    handler_depth: usize,
+    // Try statements inside the body have their finally blocks run when jumping out
+    try_depth: usize,
+}
+
+struct Try {
+    // Hidden local holding the value of a `return` while the finally block runs
+    result: u8,
+    // Scope depth and handlers outside the try and catch blocks
+    scope_depth: usize,
+    handler_depth: usize,
+    // Jumps leaving the try or catch block, to be sent through the finally block
+    exits: Vec<(usize, Exit)>,
+}
+
+#[derive(Copy, Clone)]
+enum Exit {
+    Break,
+    Continue,
+    Return,
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// A read of something assignable. If it's the last code emitted, `+=`, `++` and `--`
+/// replace it with a read-modify-write.
+#[derive(Copy, Clone)]
+struct Target {
+    get: OpCode,
+    set: OpCode,
+    // Slot, upvalue or name constant; index targets have none
+    operand: Option<u8>,
+    // Number of values, the object and index, that get and set take off the stack
+    depth: u8,
+    // Offset of the read in the chunk
+    start: usize,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    // None until the local's initializer has been compiled
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+struct Upvalue {
+    index: u8,
+    // Whether this captures a local of the enclosing function or one of its upvalues
+    is_local: bool,
+}
+
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+const MAX_ARGUMENTS: usize = 255;
+const MAX_LIST_ELEMENTS: usize = 255;
+const MAX_MAP_ENTRIES: usize = 255;
+
+// Primary is only reached by transmuting from `precedence + 1`
+#[allow(dead_code)]
 #[derive(Copy, Clone, Ord, PartialOrd, PartialEq, Eq)]
 #[repr(u8)]
 enum Precedence {
     None,
     Assignment,
+    Conditional,
     Or,
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
 }
 
 struct ParseRule<'a> {
-    prefix: Option<fn(compiler: &mut Compiler<'a>)>,
-    infix: Option<fn(compiler: &mut Compiler<'a>)>,
+    prefix: Option<fn(compiler: &mut Compiler<'a>, can_assign: bool)>,
+    infix: Option<fn(compiler: &mut Compiler<'a>, can_assign: bool)>,
     precedence: Precedence,
 }
 
@@ -41,16 +178,17 @@ impl<'a> ParseRuled<'a> for TokenKind {
         match *self {
             TokenKind::LeftParen => ParseRule {
                 prefix: Some(Compiler::grouping),
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Compiler::call),
+                precedence: Precedence::Call,
             },
             TokenKind::RightParen => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            // Statements starting with '{' are blocks, so in expression position it's a map
             TokenKind::LeftBrace => ParseRule {
-                prefix: None,
+                prefix: Some(Compiler::map),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -59,41 +197,121 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenKind::Comma => ParseRule {
+            TokenKind::LeftBracket => ParseRule {
+                prefix: Some(Compiler::list),
+                infix: Some(Compiler::index),
+                precedence: Precedence::Call,
+            },
+            TokenKind::RightBracket => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenKind::Dot => ParseRule {
+            TokenKind::Comma => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Dot => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::dot),
+                precedence: Precedence::Call,
+            },
             TokenKind::Minus => ParseRule {
                 prefix: Some(Compiler::unary),
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Term,
             },
+            TokenKind::MinusEqual => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::compound_assignment),
+                precedence: Precedence::Assignment,
+            },
+            TokenKind::MinusMinus => ParseRule {
+                prefix: Some(Compiler::prefix_update),
+                infix: Some(Compiler::postfix_update),
+                precedence: Precedence::Call,
+            },
             TokenKind::Plus => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Term,
             },
+            TokenKind::PlusEqual => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::compound_assignment),
+                precedence: Precedence::Assignment,
+            },
+            TokenKind::PlusPlus => ParseRule {
+                prefix: Some(Compiler::prefix_update),
+                infix: Some(Compiler::postfix_update),
+                precedence: Precedence::Call,
+            },
             TokenKind::Semicolon => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Colon => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Question => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::conditional),
+                precedence: Precedence::Conditional,
+            },
             TokenKind::Slash => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Factor,
             },
+            TokenKind::SlashEqual => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::compound_assignment),
+                precedence: Precedence::Assignment,
+            },
             TokenKind::Star => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Factor,
             },
+            TokenKind::StarEqual => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::compound_assignment),
+                precedence: Precedence::Assignment,
+            },
+            TokenKind::StarStar => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Exponent,
+            },
+            TokenKind::Percent => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Factor,
+            },
+            TokenKind::Ampersand => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitAnd,
+            },
+            TokenKind::Pipe => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitOr,
+            },
+            TokenKind::Caret => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::BitXor,
+            },
+            TokenKind::Tilde => ParseRule {
+                prefix: Some(Compiler::unary),
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenKind::Bang => ParseRule {
                 prefix: Some(Compiler::unary),
                 infix: None,
@@ -124,6 +342,11 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison,
             },
+            TokenKind::GreaterGreater => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Shift,
+            },
             TokenKind::Less => ParseRule {
                 prefix: None,
                 infix: Some(Compiler::binary),
@@ -134,8 +357,13 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: Some(Compiler::binary),
                 precedence: Precedence::Comparison,
             },
-            TokenKind::Identifier => ParseRule {
+            TokenKind::LessLess => ParseRule {
                 prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Shift,
+            },
+            TokenKind::Identifier => ParseRule {
+                prefix: Some(Compiler::variable),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -144,12 +372,32 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Interpolation => ParseRule {
+                prefix: Some(Compiler::interpolation),
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenKind::Number => ParseRule {
                 prefix: Some(Compiler::number),
                 infix: None,
                 precedence: Precedence::None,
             },
             TokenKind::And => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::and),
+                precedence: Precedence::And,
+            },
+            TokenKind::As => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Break => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Catch => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -159,6 +407,11 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Continue => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenKind::Else => ParseRule {
                 prefix: None,
                 infix: None,
@@ -169,6 +422,11 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Finally => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenKind::For => ParseRule {
                 prefix: None,
                 infix: None,
@@ -184,6 +442,16 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Import => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenKind::In => ParseRule {
+                prefix: None,
+                infix: Some(Compiler::binary),
+                precedence: Precedence::Comparison,
+            },
             TokenKind::Nil => ParseRule {
                 prefix: Some(Compiler::literal),
                 infix: None,
@@ -191,8 +459,8 @@ impl<'a> ParseRuled<'a> for TokenKind {
             },
             TokenKind::Or => ParseRule {
                 prefix: None,
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Compiler::or),
+                precedence: Precedence::Or,
             },
             TokenKind::Print => ParseRule {
                 prefix: None,
@@ -205,11 +473,16 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 precedence: Precedence::None,
             },
             TokenKind::Super => ParseRule {
-                prefix: None,
+                prefix: Some(Compiler::super_),
                 infix: None,
                 precedence: Precedence::None,
             },
             TokenKind::This => ParseRule {
+                prefix: Some(Compiler::this),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenKind::Throw => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -219,6 +492,11 @@ impl<'a> ParseRuled<'a> for TokenKind {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenKind::Try => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenKind::Var => ParseRule {
                 prefix: None,
                 infix: None,
@@ -250,11 +528,15 @@ macro_rules! error_at {
         }
 
         $c.panic_mode = true;
+        if $c.name_module {
+            eprint!("{}: ", $c.module);
+        }
         eprint!("[line {}] Error", $t.line);
 
         match $t.kind {
             TokenKind::End => eprint!(" at the end."),
-            _ => eprint!(" at {} ", &($t.lexeme)),
+            TokenKind::Error => (),
+            _ => eprint!(" at '{}'", $t.lexeme),
         }
 
         eprintln!(": {}", $m);
@@ -263,30 +545,42 @@ macro_rules! error_at {
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(source: &str) -> Compiler {
+    pub fn new(source: &'a str, heap: &'a mut Heap, module: Gc<Module>) -> Compiler<'a> {
         let mut scanner = Scanner::new(source);
         let current = scanner.next();
 
         Compiler {
             scanner,
+            heap,
+            module,
             current,
             previous: None,
-            chunk: None,
+            compilers: vec![FunctionCompiler::new(FunctionKind::Script, None, module)],
+            classes: Vec::new(),
+            target: None,
             had_error: false,
             panic_mode: false,
+            name_module: false,
         }
     }
 
-    pub fn compile(&mut self) -> Chunk {
-        self.chunk = Some(Chunk::new(Vec::new(), Vec::new(), Vec::new()));
-        self.expression();
-        self.consume(TokenKind::End, "Expected the end of an expression.");
-        self.end();
-        if !self.had_error {
-            self.chunk.as_ref().unwrap().clone()
-        } else {
-            panic!("this is bad")
+    /// Puts the module's path in front of each error message
+    pub fn name_module_in_errors(&mut self) {
+        self.name_module = true;
+    }
+
+    pub fn compile(&mut self) -> Result<Gc<Function>, InterpretError> {
+        while !self.matches(TokenKind::End) {
+            self.declaration();
+        }
+
+        let (function, _) = self.end();
+
+        if self.had_error {
+            return Err(InterpretError::Compile);
         }
+
+        Ok(self.heap.alloc(function))
     }
 
     fn advance(&mut self) {
@@ -294,11 +588,12 @@ impl<'a> Compiler<'a> {
 
         loop {
             self.current = self.scanner.next();
-            if self.current.kind == TokenKind::Error {
-                let lexeme = self.current.lexeme.clone();
-                self.error_at_current(&*lexeme);
+            if self.current.kind != TokenKind::Error {
+                break;
             }
-            break;
+
+            let lexeme = self.current.lexeme;
+            self.error_at_current(lexeme);
         }
     }
 
@@ -311,6 +606,19 @@ impl<'a> Compiler<'a> {
         self.error_at_current(message);
     }
 
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.kind == kind
+    }
+
+    fn matches(&mut self, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
     fn error(&mut self, message: &str) {
         let previous = self.previous.as_ref().unwrap();
         error_at!(self, previous, message);
@@ -328,29 +636,88 @@ impl<'a> Compiler<'a> {
 
     fn emit_byte(&mut self, byte: u8) {
         let line = self.previous.as_ref().unwrap().line;
-        self.chunk.as_mut().unwrap().write(byte, line);
+        self.current_chunk().write(byte, line);
+    }
+
+    fn compiler(&self) -> &FunctionCompiler<'a> {
+        self.compilers.last().unwrap()
     }
 
-    pub fn end(&mut self) {
+    fn compiler_mut(&mut self) -> &mut FunctionCompiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
+
+    fn end(&mut self) -> (Function, Vec<Upvalue>) {
         self.emit_return();
+
+        let compiler = self.compilers.pop().unwrap();
+        (compiler.function, compiler.upvalues)
     }
 
     fn emit_return(&mut self) {
+        self.emit_default_return_value();
         self.emit_op(OpCode::Return);
     }
 
+    // What a function returns without a `return` value: `this` for initializers
+    fn emit_default_return_value(&mut self) {
+        if self.compiler().kind == FunctionKind::Initializer {
+            self.emit_two(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+    }
+
     fn emit_two(&mut self, op: OpCode, data: u8) {
         self.emit_op(op);
         self.emit_byte(data);
     }
 
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let bytes = (jump as u16).to_be_bytes();
+        self.current_chunk().code[offset] = bytes[0];
+        self.current_chunk().code[offset + 1] = bytes[1];
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+
+        // +2 to account for the loop operand
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        let bytes = (offset as u16).to_be_bytes();
+        self.emit_byte(bytes[0]);
+        self.emit_byte(bytes[1]);
+    }
+
     fn emit_constant(&mut self, value: Value) {
         let index = self.make_constant(value);
         self.emit_two(OpCode::Constant, index);
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        let constant_index = self.chunk.as_mut().unwrap().write_constant(value);
+        let constant_index = self.current_chunk().write_constant(value);
         if constant_index > 255 {
             self.error("Too many constants in one chunk.");
             return 0;
@@ -359,184 +726,1427 @@ impl<'a> Compiler<'a> {
         constant_index as u8
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
-    }
-
-    fn number(&mut self) {
-        let number = self.previous.as_ref().unwrap().lexeme.parse().unwrap();
-        let value = Value::Float(number);
-        self.emit_constant(value);
-    }
+    fn declaration(&mut self) {
+        if self.matches(TokenKind::Class) {
+            self.class_declaration();
+        } else if self.matches(TokenKind::Fun) {
+            self.fun_declaration();
+        } else if self.matches(TokenKind::Var) {
+            self.var_declaration();
+        } else if self.matches(TokenKind::Import) {
+            self.import_declaration();
+        } else {
+            self.statement();
+        }
 
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(TokenKind::RightParen, "Expected ')' after expression");
+        if self.panic_mode {
+            self.synchronize();
+        }
     }
 
-    fn unary(&mut self) {
-        let operator_kind = self.previous.as_ref().unwrap().kind;
+    // `import "path" as name;` binds the module, `import "path" for a, b;` its globals
+    fn import_declaration(&mut self) {
+        if self.compiler().kind != FunctionKind::Script || self.compiler().scope_depth > 0 {
+            self.error("Can only import at the top level.");
+        }
 
-        self.parse_precedence(Precedence::Unary);
+        self.consume(TokenKind::String, "Expected a module path.");
+        let path = self.string_value();
+        let path = self.make_constant(path);
+        self.emit_two(OpCode::Import, path);
+
+        if self.matches(TokenKind::As) {
+            self.consume(TokenKind::Identifier, "Expected a module name after 'as'.");
+            let name = self.previous.as_ref().unwrap().lexeme;
+            let name = self.identifier_constant(name);
+            self.emit_two(OpCode::DefineGlobal, name);
+        } else if self.matches(TokenKind::For) {
+            loop {
+                self.consume(TokenKind::Identifier, "Expected a name to import.");
+                let name = self.previous.as_ref().unwrap().lexeme;
+                let name = self.identifier_constant(name);
+                self.emit_two(OpCode::ImportFrom, name);
+                self.emit_two(OpCode::DefineGlobal, name);
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
 
-        match operator_kind {
-            TokenKind::Bang => self.emit_op(OpCode::Not),
-            TokenKind::Minus => self.emit_op(OpCode::Negate),
-            _ => self.error(&*format!("Unexpected unary operator: {:?}", operator_kind)),
+            self.emit_op(OpCode::Pop);
+        } else {
+            self.error_at_current("Expected 'as' or 'for' after the module path.");
         }
+
+        self.consume(TokenKind::Semicolon, "Expected ';' after import.");
     }
 
-    fn binary(&mut self) {
-        let operator_kind = self.previous.as_ref().unwrap().kind;
-        let rule = operator_kind.get_parse_rule();
-        let next_precedence: Precedence =
-            unsafe { std::mem::transmute((rule.precedence as u8) + 1) };
-        self.parse_precedence(next_precedence);
+    fn class_declaration(&mut self) {
+        self.consume(TokenKind::Identifier, "Expected a class name.");
+        let name = self.previous.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(name);
+        self.declare_variable();
 
-        match operator_kind {
-            TokenKind::Plus => self.emit_op(OpCode::Add),
-            TokenKind::Minus => self.emit_op(OpCode::Subtract),
-            TokenKind::Star => self.emit_op(OpCode::Multiply),
-            TokenKind::Slash => self.emit_op(OpCode::Divide),
-            TokenKind::EqualEqual => self.emit_op(OpCode::Equal),
-            TokenKind::BangEqual => {
-                self.emit_op(OpCode::Equal);
-                self.emit_op(OpCode::Not);
-            }
-            TokenKind::Less => self.emit_op(OpCode::Less),
-            TokenKind::LessEqual => {
-                self.emit_op(OpCode::Greater);
-                self.emit_op(OpCode::Not);
-            }
-            TokenKind::Greater => self.emit_op(OpCode::Greater),
-            TokenKind::GreaterEqual => {
-                self.emit_op(OpCode::Less);
-                self.emit_op(OpCode::Not);
+        self.emit_two(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenKind::Less) {
+            self.consume(TokenKind::Identifier, "Expected a superclass name.");
+            self.variable(false);
+
+            if name == self.previous.as_ref().unwrap().lexeme {
+                self.error("A class can't inherit from itself.");
             }
-            _ => self.error(&*format!("Unexpected binary operator: {:?}", operator_kind)),
+
+            self.begin_scope();
+            self.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(name, false);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
         }
-    }
 
-    fn literal(&mut self) {
-        match self.previous.as_ref().unwrap().kind {
-            TokenKind::True => self.emit_op(OpCode::True),
-            TokenKind::False => self.emit_op(OpCode::False),
-            TokenKind::Nil => self.emit_op(OpCode::Nil),
-            _ => unreachable!(
-                "Literal not handled {}",
-                self.previous.as_ref().unwrap().lexeme
-            ),
+        self.named_variable(name, false);
+        self.consume(TokenKind::LeftBrace, "Expected '{' before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::End) {
+            self.method();
         }
-    }
+        self.consume(TokenKind::RightBrace, "Expected '}' after class body.");
+        self.emit_op(OpCode::Pop);
 
-    fn string(&mut self) {
-        let value = self
-            .previous
-            .as_ref()
-            .unwrap()
-            .lexeme
-            .trim_matches('"')
-            .to_string();
-        self.emit_constant(Value::Str(value));
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
+    fn method(&mut self) {
+        self.consume(TokenKind::Identifier, "Expected a method name.");
+        let name = self.previous.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(name);
 
-        let prefix_rule = self.previous.as_ref().unwrap().kind.get_parse_rule().prefix;
-        if let Some(rule) = prefix_rule {
-            rule(self);
+        let kind = if name == "init" {
+            FunctionKind::Initializer
         } else {
-            self.error("Expected an expression.");
-            return;
-        }
+            FunctionKind::Method
+        };
 
-        while precedence <= self.current.kind.get_parse_rule().precedence {
-            self.advance();
-            let previous_token = self.previous.as_ref().unwrap();
-            let parse_rule = previous_token.kind.get_parse_rule();
-            let infix_rule = parse_rule.infix.unwrap();
-            infix_rule(self);
-        }
+        self.function(kind);
+        self.emit_two(OpCode::Method, name_constant);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expected a function name.");
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
 
-    #[test]
-    fn last_opcode_is_return() {
-        let mut compiler = Compiler::new("10");
-        let chunk = compiler.compile();
+    fn function(&mut self, kind: FunctionKind) {
+        let name = self.previous.as_ref().unwrap().lexeme.to_string();
+        let module = self.module;
+        self.compilers
+            .push(FunctionCompiler::new(kind, Some(name), module));
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expected '(' after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.compiler_mut().function.arity += 1;
+                if self.compiler().function.arity > MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expected a parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expected ')' after parameters.");
 
-        assert_eq!(chunk.code[2], OpCode::Return as u8);
-    }
+        self.consume(TokenKind::LeftBrace, "Expected '{' before function body.");
+        self.block();
 
-    #[test]
-    fn constant() {
-        let mut compiler = Compiler::new("10");
-        let chunk = compiler.compile();
+        let (function, upvalues) = self.end();
+        let function = self.heap.alloc(function);
+        let constant = self.make_constant(Value::Function(function));
+        self.emit_two(OpCode::Closure, constant);
 
-        assert_eq!(chunk.constants[0], Value::Float(10.0));
-        assert_eq!(chunk.code[0], OpCode::Constant as u8);
-        assert_eq!(chunk.code[1], 0);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
     }
 
-    #[test]
-    fn constant_string() {
-        let mut compiler = Compiler::new(r#""constant""#);
-        let chunk = compiler.compile();
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expected a variable name.");
 
-        assert_eq!(chunk.constants[0], Value::Str(String::from("constant")));
-        assert_eq!(chunk.code[0], OpCode::Constant as u8);
-        assert_eq!(chunk.code[1], 0);
-    }
+        if self.matches(TokenKind::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
 
-    #[test]
-    fn negation() {
-        let mut compiler = Compiler::new("-1");
-        let chunk = compiler.compile();
+        self.consume(
+            TokenKind::Semicolon,
+            "Expected ';' after variable declaration.",
+        );
 
-        assert_eq!(chunk.code[2], OpCode::Negate as u8);
+        self.define_variable(global);
     }
 
-    #[test]
-    fn sum() {
-        let mut compiler = Compiler::new("1 + 2");
-        let chunk = compiler.compile();
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(TokenKind::Identifier, message);
 
-        assert_eq!(chunk.code[4], OpCode::Add as u8);
+        self.declare_variable();
+        if self.compiler().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous.as_ref().unwrap().lexeme;
+        self.identifier_constant(name)
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.compiler().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.as_ref().unwrap().lexeme;
+
+        let mut redeclared = false;
+        for local in self.compiler().locals.iter().rev() {
+            if let Some(depth) = local.depth {
+                if depth < scope_depth {
+                    break;
+                }
+            }
+
+            if local.name == name {
+                redeclared = true;
+                break;
+            }
+        }
+
+        if redeclared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: &'a str) {
+        if self.compiler().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler_mut();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+
+        compiler.locals.last_mut().unwrap().depth = Some(compiler.scope_depth);
+    }
+
+    fn resolve_local(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let locals = &self.compilers[compiler].locals;
+        let position = locals.iter().rposition(|local| local.name == name);
+
+        if let Some(index) = position {
+            if self.compilers[compiler].locals[index].depth.is_none() {
+                self.error("Can't read local variable in its own initializer.");
+            }
+        }
+
+        position.map(|index| index as u8)
+    }
+
+    fn resolve_upvalue(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        if compiler == 0 {
+            return None;
+        }
+
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
+        let existing = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+
+        if let Some(existing) = existing {
+            return existing as u8;
+        }
+
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let function_compiler = &mut self.compilers[compiler];
+        function_compiler.upvalues.push(Upvalue { index, is_local });
+        function_compiler.function.upvalue_count = function_compiler.upvalues.len();
+        (function_compiler.upvalues.len() - 1) as u8
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> u8 {
+        let name = self.heap.intern(name);
+        self.make_constant(Value::Str(name))
+    }
+
+    fn define_variable(&mut self, global: u8) {
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_two(OpCode::DefineGlobal, global);
+    }
+
+    fn statement(&mut self) {
+        if self.matches(TokenKind::Print) {
+            self.print_statement();
+        } else if self.matches(TokenKind::Return) {
+            self.return_statement();
+        } else if self.matches(TokenKind::If) {
+            self.if_statement();
+        } else if self.matches(TokenKind::While) {
+            self.while_statement();
+        } else if self.matches(TokenKind::For) {
+            self.for_statement();
+        } else if self.matches(TokenKind::Try) {
+            self.try_statement();
+        } else if self.matches(TokenKind::Throw) {
+            self.throw_statement();
+        } else if self.matches(TokenKind::Break) {
+            self.break_statement();
+        } else if self.matches(TokenKind::Continue) {
+            self.continue_statement();
+        } else if self.matches(TokenKind::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::End) {
+            self.declaration();
+        }
+
+        self.consume(TokenKind::RightBrace, "Expected '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler_mut().scope_depth -= 1;
+
+        let depth = self.compiler().scope_depth;
+        let remaining = self.discard_locals(depth);
+        self.compiler_mut().locals.truncate(remaining);
+    }
+
+    /// Emits code to pop the locals deeper than `depth`, closing captured ones, and
+    /// returns how many locals are left. The compiler still tracks the popped locals.
+    fn discard_locals(&mut self, depth: usize) -> usize {
+        let mut remaining = self.compiler().locals.len();
+
+        loop {
+            let is_captured = match self.compiler().locals[..remaining].last() {
+                Some(local) if local.depth.is_none_or(|local_depth| local_depth > depth) => {
+                    local.is_captured
+                }
+                _ => break,
+            };
+
+            if is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+
+            remaining -= 1;
+        }
+
+        remaining
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.compiler().scope_depth;
+        let handler_depth = self.compiler().handler_depth;
+        let try_depth = self.compiler().tries.len();
+        self.compiler_mut().loops.push(Loop {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+            handler_depth,
+            try_depth,
+        });
+    }
+
+    fn end_loop(&mut self) {
+        let exited = self.compiler_mut().loops.pop().unwrap();
+        for jump in exited.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expected '(' after 'if'.");
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expected ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+
+        if self.matches(TokenKind::Else) {
+            self.statement();
+        }
+
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+
+        self.consume(TokenKind::LeftParen, "Expected '(' after 'while'.");
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expected ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+
+        self.consume(TokenKind::LeftParen, "Expected '(' after 'for'.");
+        if self.matches(TokenKind::Semicolon) {
+            // No initializer
+        } else if self.matches(TokenKind::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+
+        let mut exit_jump = None;
+        if !self.matches(TokenKind::Semicolon) {
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expected ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        if !self.matches(TokenKind::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
+
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(TokenKind::RightParen, "Expected ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop);
+        }
+
+        self.end_loop();
+        self.end_scope();
+    }
+
+    fn break_statement(&mut self) {
+        if self.compiler().loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.");
+        } else {
+            self.emit_exit(Exit::Break);
+        }
+
+        self.consume(TokenKind::Semicolon, "Expected ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        if self.compiler().loops.is_empty() {
+            self.error("Can't use 'continue' outside of a loop.");
+        } else {
+            self.emit_exit(Exit::Continue);
+        }
+
+        self.consume(TokenKind::Semicolon, "Expected ';' after 'continue'.");
+    }
+
+    /// Emits code to leave the innermost loop's body, or the function for a return
+    /// with its value on the stack. If a try statement is in the way, this only leaves
+    /// the try or catch block, jumping to code emitted with the finally block, which
+    /// carries on leaving once that has run.
+    fn emit_exit(&mut self, exit: Exit) {
+        let (scope_depth, handler_depth, try_depth) = match exit {
+            Exit::Return => (0, 0, 0),
+            Exit::Break | Exit::Continue => {
+                let innermost = self.compiler().loops.last().unwrap();
+                (
+                    innermost.scope_depth,
+                    innermost.handler_depth,
+                    innermost.try_depth,
+                )
+            }
+        };
+
+        if self.compiler().tries.len() > try_depth {
+            let innermost = self.compiler().tries.last().unwrap();
+            let (result, scope_depth, handler_depth) = (
+                innermost.result,
+                innermost.scope_depth,
+                innermost.handler_depth,
+            );
+
+            if let Exit::Return = exit {
+                self.emit_two(OpCode::SetLocal, result);
+                self.emit_op(OpCode::Pop);
+            }
+            self.discard_locals(scope_depth);
+            for _ in handler_depth..self.compiler().handler_depth {
+                self.emit_op(OpCode::PopHandler);
+            }
+
+            let jump = self.emit_jump(OpCode::Jump);
+            let innermost = self.compiler_mut().tries.last_mut().unwrap();
+            innermost.exits.push((jump, exit));
+            return;
+        }
+
+        // Handlers go with the frame on return
+        if let Exit::Return = exit {
+            self.emit_op(OpCode::Return);
+            return;
+        }
+
+        self.discard_locals(scope_depth);
+        for _ in handler_depth..self.compiler().handler_depth {
+            self.emit_op(OpCode::PopHandler);
+        }
+
+        if let Exit::Continue = exit {
+            let start = self.compiler().loops.last().unwrap().start;
+            self.emit_loop(start);
+        } else {
+            let jump = self.emit_jump(OpCode::Jump);
+            let innermost = self.compiler_mut().loops.last_mut().unwrap();
+            innermost.breaks.push(jump);
+        }
+    }
+
+    // Carries on leaving by a jump that was sent through a finally block
+    fn resume_exit(&mut self, exit: Exit, result: u8) {
+        if let Exit::Return = exit {
+            self.emit_two(OpCode::GetLocal, result);
+        }
+        self.emit_exit(exit);
+    }
+
+    /// Compiles `try {} catch (e) {} finally {}`, where either clause may be left out.
+    ///
+    /// A throw unwinds to the innermost handler, which resumes with the exception
+    /// pushed at the stack height the handler was installed at. Two handlers are
+    /// installed: the inner one leads to the catch block, and the outer one, still in
+    /// place while the catch block runs, to the finally block or a rethrow. The finally
+    /// block is compiled once, with two hidden locals: the exception and what to do
+    /// after it. That's false to carry on, true to rethrow, or the index of a break,
+    /// continue or return that left the try or catch block, with a return's value kept
+    /// in a hidden local below the handlers.
+    fn try_statement(&mut self) {
+        self.consume(TokenKind::LeftBrace, "Expected '{' after 'try'.");
+        self.begin_scope();
+        self.emit_op(OpCode::Nil);
+        let result = self.add_hidden_local("");
+        let scope_depth = self.compiler().scope_depth;
+        let handler_depth = self.compiler().handler_depth;
+        self.compiler_mut().tries.push(Try {
+            result,
+            scope_depth,
+            handler_depth,
+            exits: Vec::new(),
+        });
+
+        let outer = self.emit_jump(OpCode::PushHandler);
+        let inner = self.emit_jump(OpCode::PushHandler);
+        self.compiler_mut().handler_depth += 2;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.compiler_mut().handler_depth -= 2;
+        self.emit_op(OpCode::PopHandler);
+        self.emit_op(OpCode::PopHandler);
+        let mut exits = vec![self.emit_jump(OpCode::Jump)];
+
+        self.patch_jump(inner);
+        let has_catch = self.matches(TokenKind::Catch);
+        if has_catch {
+            self.consume(TokenKind::LeftParen, "Expected '(' after 'catch'.");
+            self.consume(
+                TokenKind::Identifier,
+                "Expected an exception variable name.",
+            );
+            let name = self.previous.as_ref().unwrap().lexeme;
+            self.consume(
+                TokenKind::RightParen,
+                "Expected ')' after exception variable.",
+            );
+            self.consume(TokenKind::LeftBrace, "Expected '{' after catch clause.");
+
+            self.begin_scope();
+            self.add_hidden_local(name);
+            self.compiler_mut().handler_depth += 1;
+            self.block();
+            self.compiler_mut().handler_depth -= 1;
+            self.end_scope();
+            self.emit_op(OpCode::PopHandler);
+            exits.push(self.emit_jump(OpCode::Jump));
+        } else {
+            self.emit_op(OpCode::PopHandler);
+        }
+        let left = self.compiler_mut().tries.pop().unwrap().exits;
+
+        // Any exception still pending is on top of the stack here
+        self.patch_jump(outer);
+        if self.matches(TokenKind::Finally) {
+            self.consume(TokenKind::LeftBrace, "Expected '{' after 'finally'.");
+
+            self.emit_op(OpCode::True);
+            let mut finally_jumps = vec![self.emit_jump(OpCode::Jump)];
+            for (index, &(jump, _)) in left.iter().enumerate() {
+                self.patch_jump(jump);
+                self.emit_op(OpCode::Nil);
+                self.emit_constant(Value::Float(index as f64));
+                finally_jumps.push(self.emit_jump(OpCode::Jump));
+            }
+            for exit in exits {
+                self.patch_jump(exit);
+            }
+            self.emit_op(OpCode::Nil);
+            self.emit_op(OpCode::False);
+            for jump in finally_jumps {
+                self.patch_jump(jump);
+            }
+
+            self.begin_scope();
+            let exception = self.add_hidden_local("");
+            let pending = self.add_hidden_local("");
+            self.block();
+
+            for (index, &(_, exit)) in left.iter().enumerate() {
+                self.emit_two(OpCode::GetLocal, pending);
+                self.emit_constant(Value::Float(index as f64));
+                self.emit_op(OpCode::Equal);
+                let other_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.resume_exit(exit, result);
+                self.patch_jump(other_jump);
+                self.emit_op(OpCode::Pop);
+            }
+
+            self.emit_two(OpCode::GetLocal, pending);
+            let done_jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_op(OpCode::Pop);
+            self.emit_two(OpCode::GetLocal, exception);
+            self.emit_op(OpCode::Throw);
+            self.patch_jump(done_jump);
+            self.emit_op(OpCode::Pop);
+            self.end_scope();
+        } else {
+            if !has_catch {
+                self.error_at_current("Expected 'catch' or 'finally' after try block.");
+            }
+
+            self.emit_op(OpCode::Throw);
+            for (jump, exit) in left {
+                self.patch_jump(jump);
+                self.resume_exit(exit, result);
+            }
+            for exit in exits {
+                self.patch_jump(exit);
+            }
+        }
+
+        self.end_scope();
+    }
+
+    // Declares an initialized local for a value the compiler has arranged to be on the stack
+    fn add_hidden_local(&mut self, name: &'a str) -> u8 {
+        self.add_local(name);
+        self.mark_initialized();
+        (self.compiler().locals.len() - 1) as u8
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expected ';' after thrown value.");
+        self.emit_op(OpCode::Throw);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler().kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenKind::Semicolon) {
+            self.emit_default_return_value();
+        } else {
+            if self.compiler().kind == FunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expected ';' after return value.");
+        }
+
+        self.emit_exit(Exit::Return);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expected ';' after value.");
+        self.emit_op(OpCode::Print);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expected ';' after expression.");
+        self.emit_op(OpCode::Pop);
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.kind != TokenKind::End {
+            if self.previous.as_ref().unwrap().kind == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Break
+                | TokenKind::Continue
+                | TokenKind::Try
+                | TokenKind::Throw
+                | TokenKind::Import
+                | TokenKind::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.previous.as_ref().unwrap().lexeme;
+        // Literals with a fractional part are floats; the rest are integers, big
+        // ones if they don't fit in an `Int`
+        let value = if lexeme.contains('.') {
+            Value::Float(lexeme.parse().unwrap())
+        } else {
+            match lexeme.parse() {
+                Ok(number) => Value::Int(number),
+                Err(_) => Value::BigInt(self.heap.alloc(BigInt::parse(lexeme).unwrap())),
+            }
+        };
+        self.emit_constant(value);
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expected ')' after expression");
+        // `(a or b) += 1` would rewrite the read of `b` alone
+        self.target = None;
+    }
+
+    fn compound_assignment(&mut self, _can_assign: bool) {
+        let op = match self.previous.as_ref().unwrap().kind {
+            TokenKind::PlusEqual => OpCode::Add,
+            TokenKind::MinusEqual => OpCode::Subtract,
+            TokenKind::StarEqual => OpCode::Multiply,
+            TokenKind::SlashEqual => OpCode::Divide,
+            kind => {
+                self.error(&format!("Unexpected assignment operator: {:?}", kind));
+                return;
+            }
+        };
+
+        match self.take_target() {
+            Some(target) => self.emit_update(target, false, |compiler| {
+                compiler.expression();
+                compiler.emit_op(op);
+            }),
+            None => self.error("Invalid assignment target."),
+        }
+    }
+
+    fn prefix_update(&mut self, _can_assign: bool) {
+        let op = self.update_op();
+        self.parse_precedence(Precedence::Call);
+        self.increment(op, false);
+    }
+
+    fn postfix_update(&mut self, _can_assign: bool) {
+        let op = self.update_op();
+        self.increment(op, true);
+    }
+
+    fn update_op(&self) -> OpCode {
+        if self.previous.as_ref().unwrap().kind == TokenKind::PlusPlus {
+            OpCode::Add
+        } else {
+            OpCode::Subtract
+        }
+    }
+
+    fn increment(&mut self, op: OpCode, postfix: bool) {
+        match self.take_target() {
+            Some(target) => self.emit_update(target, postfix, |compiler| {
+                compiler.emit_constant(Value::Int(1));
+                compiler.emit_op(op);
+            }),
+            None => self.error("Invalid increment target."),
+        }
+    }
+
+    fn emit_target(&mut self, get: OpCode, set: OpCode, operand: Option<u8>, depth: u8) {
+        let start = self.current_chunk().code.len();
+        self.emit_target_op(get, operand);
+        self.target = Some(Target {
+            get,
+            set,
+            operand,
+            depth,
+            start,
+        });
+    }
+
+    fn emit_target_op(&mut self, op: OpCode, operand: Option<u8>) {
+        self.emit_op(op);
+        if let Some(operand) = operand {
+            self.emit_byte(operand);
+        }
+    }
+
+    // Removes the read of the target just parsed, or returns None if the expression
+    // wasn't one
+    fn take_target(&mut self) -> Option<Target> {
+        let target = self.target.take()?;
+        let chunk = self.current_chunk();
+
+        if chunk.code.len() != target.start + 1 + target.operand.is_some() as usize {
+            return None;
+        }
+
+        chunk.code.truncate(target.start);
+        chunk.lines.truncate(target.start);
+        Some(target)
+    }
+
+    // Reads the target, runs `update` on the value and stores the result, with the
+    // target's object and index evaluated only once. The expression's value is the
+    // result, or the original value for postfix operators.
+    fn emit_update(&mut self, target: Target, postfix: bool, update: impl FnOnce(&mut Self)) {
+        // The get and the set each need their own object and index
+        for _ in 0..target.depth {
+            self.emit_two(OpCode::Copy, target.depth - 1);
+        }
+        self.emit_target_op(target.get, target.operand);
+
+        if postfix {
+            self.emit_two(OpCode::Copy, 0);
+            if target.depth > 0 {
+                self.emit_two(OpCode::Bury, target.depth + 1);
+            }
+        }
+
+        update(self);
+        self.emit_target_op(target.set, target.operand);
+
+        if postfix {
+            self.emit_op(OpCode::Pop);
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator_kind = self.previous.as_ref().unwrap().kind;
+
+        self.parse_precedence(Precedence::Unary);
+
+        match operator_kind {
+            TokenKind::Bang => self.emit_op(OpCode::Not),
+            TokenKind::Minus => self.emit_op(OpCode::Negate),
+            TokenKind::Tilde => self.emit_op(OpCode::BitNot),
+            _ => self.error(&format!("Unexpected unary operator: {:?}", operator_kind)),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator_kind = self.previous.as_ref().unwrap().kind;
+        let rule = operator_kind.get_parse_rule();
+        if operator_kind == TokenKind::StarStar {
+            // Right-associative, so `a ** b ** c` is `a ** (b ** c)`
+            self.parse_precedence(rule.precedence);
+        } else {
+            let next_precedence: Precedence =
+                unsafe { std::mem::transmute((rule.precedence as u8) + 1) };
+            self.parse_precedence(next_precedence);
+        }
+
+        match operator_kind {
+            TokenKind::Plus => self.emit_op(OpCode::Add),
+            TokenKind::Minus => self.emit_op(OpCode::Subtract),
+            TokenKind::Star => self.emit_op(OpCode::Multiply),
+            TokenKind::Slash => self.emit_op(OpCode::Divide),
+            TokenKind::Percent => self.emit_op(OpCode::Modulo),
+            TokenKind::StarStar => self.emit_op(OpCode::Power),
+            TokenKind::Ampersand => self.emit_op(OpCode::BitAnd),
+            TokenKind::Pipe => self.emit_op(OpCode::BitOr),
+            TokenKind::Caret => self.emit_op(OpCode::BitXor),
+            TokenKind::LessLess => self.emit_op(OpCode::ShiftLeft),
+            TokenKind::GreaterGreater => self.emit_op(OpCode::ShiftRight),
+            TokenKind::EqualEqual => self.emit_op(OpCode::Equal),
+            TokenKind::BangEqual => {
+                self.emit_op(OpCode::Equal);
+                self.emit_op(OpCode::Not);
+            }
+            TokenKind::Less => self.emit_op(OpCode::Less),
+            TokenKind::LessEqual => {
+                self.emit_op(OpCode::Greater);
+                self.emit_op(OpCode::Not);
+            }
+            TokenKind::Greater => self.emit_op(OpCode::Greater),
+            TokenKind::GreaterEqual => {
+                self.emit_op(OpCode::Less);
+                self.emit_op(OpCode::Not);
+            }
+            TokenKind::In => self.emit_op(OpCode::Contains),
+            _ => self.error(&format!("Unexpected binary operator: {:?}", operator_kind)),
+        }
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let argument_count = self.argument_list();
+        self.emit_two(OpCode::Call, argument_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut argument_count = 0;
+
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.expression();
+
+                if argument_count == MAX_ARGUMENTS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                argument_count += 1;
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightParen, "Expected ')' after arguments.");
+        argument_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenKind::Identifier, "Expected a property name after '.'.");
+        let name = self.previous.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(name);
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            self.expression();
+            self.emit_two(OpCode::SetProperty, name_constant);
+        } else if self.matches(TokenKind::LeftParen) {
+            let argument_count = self.argument_list();
+            self.emit_two(OpCode::Invoke, name_constant);
+            self.emit_byte(argument_count);
+        } else {
+            self.emit_target(
+                OpCode::GetProperty,
+                OpCode::SetProperty,
+                Some(name_constant),
+                1,
+            );
+        }
+    }
+
+    fn list(&mut self, _can_assign: bool) {
+        let mut element_count = 0;
+
+        if !self.check(TokenKind::RightBracket) {
+            loop {
+                self.expression();
+
+                if element_count == MAX_LIST_ELEMENTS {
+                    self.error("Can't have more than 255 elements in a list literal.");
+                }
+                element_count += 1;
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBracket, "Expected ']' after list elements.");
+        self.emit_two(OpCode::BuildList, element_count as u8);
+    }
+
+    fn map(&mut self, _can_assign: bool) {
+        let mut entry_count = 0;
+
+        if !self.check(TokenKind::RightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenKind::Colon, "Expected ':' after map key.");
+                self.expression();
+
+                if entry_count == MAX_MAP_ENTRIES {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expected '}' after map entries.");
+        self.emit_two(OpCode::BuildMap, entry_count as u8);
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenKind::RightBracket, "Expected ']' after index.");
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            self.expression();
+            self.emit_op(OpCode::IndexSet);
+        } else {
+            self.emit_target(OpCode::IndexGet, OpCode::IndexSet, None, 2);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+
+        self.consume(TokenKind::Dot, "Expected '.' after 'super'.");
+        self.consume(TokenKind::Identifier, "Expected a superclass method name.");
+        let name = self.previous.as_ref().unwrap().lexeme;
+        let name_constant = self.identifier_constant(name);
+
+        self.named_variable("this", false);
+        if self.matches(TokenKind::LeftParen) {
+            let argument_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_two(OpCode::SuperInvoke, name_constant);
+            self.emit_byte(argument_count);
+        } else {
+            self.named_variable("super", false);
+            self.emit_two(OpCode::GetSuper, name_constant);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+        self.target = None;
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+        self.target = None;
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+        self.target = None;
+    }
+
+    fn conditional(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_op(OpCode::Pop);
+        self.expression();
+        self.consume(
+            TokenKind::Colon,
+            "Expected ':' after the first branch of a conditional.",
+        );
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+        // Same precedence rather than the next, so `a ? b : c ? d : e` nests on the right
+        self.parse_precedence(Precedence::Conditional);
+
+        self.patch_jump(end_jump);
+        self.target = None;
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous.as_ref().unwrap().kind {
+            TokenKind::True => self.emit_op(OpCode::True),
+            TokenKind::False => self.emit_op(OpCode::False),
+            TokenKind::Nil => self.emit_op(OpCode::Nil),
+            _ => unreachable!(
+                "Literal not handled {}",
+                self.previous.as_ref().unwrap().lexeme
+            ),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        self.string_segment();
+    }
+
+    // "a ${b} c" arrives as an Interpolation token for `"a ${`, the tokens of `b`,
+    // then a String token for `} c"`; each part is stringified and concatenated
+    fn interpolation(&mut self, _can_assign: bool) {
+        self.string_segment();
+
+        loop {
+            self.expression();
+            self.emit_op(OpCode::Stringify);
+            self.emit_op(OpCode::Add);
+
+            let more = self.matches(TokenKind::Interpolation);
+            // Without the rest of the string there's no segment to add
+            if !more && !self.matches(TokenKind::String) {
+                self.error_at_current("Expected '}' after interpolated expression.");
+                return;
+            }
+
+            self.string_segment();
+            self.emit_op(OpCode::Add);
+
+            if !more {
+                break;
+            }
+        }
+    }
+
+    fn string_segment(&mut self) {
+        let value = self.string_value();
+        self.emit_constant(value);
+    }
+
+    /// The text of the previous string token without its delimiters.
+    fn string_value(&mut self) -> Value {
+        let token = self.previous.clone().unwrap();
+        // Opens with '"' or with the '}' closing an interpolation
+        let raw = &token.lexeme[1..];
+        let raw = raw
+            .strip_suffix("${")
+            .or_else(|| raw.strip_suffix('"'))
+            .unwrap_or(raw);
+
+        let value = self.unescape(raw, token.line);
+        Value::Str(self.heap.intern_owned(value))
+    }
+
+    /// Replaces escape sequences in the text of a string token ending on `line`.
+    fn unescape(&mut self, raw: &'a str, line: u32) -> String {
+        let mut value = String::with_capacity(raw.len());
+        let mut chars = raw.char_indices();
+
+        while let Some((start, c)) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+
+            let escaped = match chars.next() {
+                Some((_, 'n')) => Some('\n'),
+                Some((_, 't')) => Some('\t'),
+                Some((_, 'r')) => Some('\r'),
+                Some((_, '"')) => Some('"'),
+                Some((_, '\\')) => Some('\\'),
+                Some((_, '$')) => Some('$'),
+                Some((_, 'u')) => unicode_escape(&mut chars),
+                _ => None,
+            };
+
+            match escaped {
+                Some(escaped) => value.push(escaped),
+                None => {
+                    let escape = &raw[start..chars.offset()];
+                    // The token's line is where it ends, so count back to the escape
+                    let line = line - raw[start..].matches('\n').count() as u32;
+                    self.invalid_escape(escape, line);
+                }
+            }
+        }
+
+        value
+    }
+
+    fn invalid_escape(&mut self, escape: &'a str, line: u32) {
+        let token = Token::new(TokenKind::String, escape, line);
+        error_at!(self, token, "Invalid escape sequence.");
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.as_ref().unwrap().lexeme;
+        self.named_variable(name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let compiler = self.compilers.len() - 1;
+        let (get_op, set_op, index) = if let Some(slot) = self.resolve_local(compiler, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(upvalue) = self.resolve_upvalue(compiler, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, upvalue)
+        } else {
+            let index = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, index)
+        };
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            self.expression();
+            self.emit_two(set_op, index);
+        } else {
+            self.emit_target(get_op, set_op, Some(index), 0);
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        self.target = None;
+
+        let prefix_rule = self.previous.as_ref().unwrap().kind.get_parse_rule().prefix;
+        let can_assign = precedence <= Precedence::Assignment;
+        if let Some(rule) = prefix_rule {
+            rule(self, can_assign);
+        } else {
+            self.error("Expected an expression.");
+            return;
+        }
+
+        while precedence <= self.current.kind.get_parse_rule().precedence {
+            self.advance();
+            let previous_token = self.previous.as_ref().unwrap();
+            let parse_rule = previous_token.kind.get_parse_rule();
+            let infix_rule = parse_rule.infix.unwrap();
+            infix_rule(self, can_assign);
+        }
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+}
+
+// Parses the `{XXXX}` following `\\u`, with one to six hex digits
+fn unicode_escape(chars: &mut std::str::CharIndices) -> Option<char> {
+    if chars.next()?.1 != '{' {
+        return None;
+    }
+
+    let mut code = 0;
+    let mut digits = 0;
+    loop {
+        let (_, c) = chars.next()?;
+        if c == '}' {
+            break;
+        }
+
+        digits += 1;
+        if digits > 6 {
+            return None;
+        }
+        code = code * 16 + c.to_digit(16)?;
+    }
+
+    if digits == 0 {
+        return None;
+    }
+
+    char::from_u32(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn compile(source: &str) -> Result<Chunk, InterpretError> {
+        // Leaked so that constants stay valid for the rest of the test
+        let heap = Box::leak(Box::new(Heap::new()));
+        let module = heap.alloc(Module::new(PathBuf::new()));
+        let mut compiler = Compiler::new(source, heap, module);
+        compiler.compile().map(|function| function.chunk.clone())
+    }
+
+    #[test]
+    fn last_opcode_is_return() {
+        let chunk = compile("10;").unwrap();
+
+        assert_eq!(chunk.code[4], OpCode::Return as u8);
+    }
+
+    #[test]
+    fn constant() {
+        let chunk = compile("10;").unwrap();
+
+        assert!(matches!(chunk.constants[0], Value::Int(10)));
+        assert_eq!(chunk.code[0], OpCode::Constant as u8);
+        assert_eq!(chunk.code[1], 0);
+    }
+
+    #[test]
+    fn constant_string() {
+        let chunk = compile(r#""constant";"#).unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "constant");
+        assert_eq!(chunk.code[0], OpCode::Constant as u8);
+        assert_eq!(chunk.code[1], 0);
+    }
+
+    #[test]
+    fn identical_strings_share_an_object() {
+        let chunk = compile(r#"var s = "s"; s == "s";"#).unwrap();
+
+        match (chunk.constants[0], chunk.constants[1], chunk.constants[3]) {
+            (Value::Str(name), Value::Str(value), Value::Str(compared)) => {
+                assert!(Gc::ptr_eq(name, value));
+                assert!(Gc::ptr_eq(value, compared));
+            }
+            _ => panic!("Expected string constants"),
+        }
+    }
+
+    #[test]
+    fn negation() {
+        let chunk = compile("-1;").unwrap();
+
+        assert_eq!(chunk.code[2], OpCode::Negate as u8);
+    }
+
+    #[test]
+    fn sum() {
+        let chunk = compile("1 + 2;").unwrap();
+
+        assert_eq!(chunk.code[4], OpCode::Add as u8);
     }
 
     #[test]
     fn product() {
-        let mut compiler = Compiler::new("1 * 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 * 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Multiply as u8);
     }
 
     #[test]
     fn difference() {
-        let mut compiler = Compiler::new("1 - 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 - 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Subtract as u8);
     }
 
     #[test]
     fn quotient() {
-        let mut compiler = Compiler::new("1 / 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 / 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Divide as u8);
     }
 
     #[test]
     fn arithmetic_precedence() {
-        let mut compiler = Compiler::new("1 + 2 * 10");
-        let chunk = compiler.compile();
+        let chunk = compile("1 + 2 * 10;").unwrap();
         println!("{:?}", chunk.code);
         assert_eq!(chunk.code[6], OpCode::Multiply as u8);
         assert_eq!(chunk.code[7], OpCode::Add as u8);
@@ -544,8 +2154,7 @@ mod tests {
 
     #[test]
     fn coerced_precedence() {
-        let mut compiler = Compiler::new("(1 + 2) * 10");
-        let chunk = compiler.compile();
+        let chunk = compile("(1 + 2) * 10;").unwrap();
         println!("{:?}", chunk.code);
         assert_eq!(chunk.code[4], OpCode::Add as u8);
         assert_eq!(chunk.code[7], OpCode::Multiply as u8);
@@ -553,16 +2162,14 @@ mod tests {
 
     #[test]
     fn equal() {
-        let mut compiler = Compiler::new("1 == 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 == 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Equal as u8);
     }
 
     #[test]
     fn not_equal() {
-        let mut compiler = Compiler::new("1 != 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 != 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Equal as u8);
         assert_eq!(chunk.code[5], OpCode::Not as u8);
@@ -570,16 +2177,14 @@ mod tests {
 
     #[test]
     fn greater() {
-        let mut compiler = Compiler::new("1 > 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 > 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Greater as u8);
     }
 
     #[test]
     fn greater_equal() {
-        let mut compiler = Compiler::new("1 >= 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 >= 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Less as u8);
         assert_eq!(chunk.code[5], OpCode::Not as u8);
@@ -587,16 +2192,14 @@ mod tests {
 
     #[test]
     fn less() {
-        let mut compiler = Compiler::new("1 < 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 < 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Less as u8);
     }
 
     #[test]
     fn less_equal() {
-        let mut compiler = Compiler::new("1 <= 2");
-        let chunk = compiler.compile();
+        let chunk = compile("1 <= 2;").unwrap();
 
         assert_eq!(chunk.code[4], OpCode::Greater as u8);
         assert_eq!(chunk.code[5], OpCode::Not as u8);
@@ -604,25 +2207,565 @@ mod tests {
 
     #[test]
     fn literal_true() {
-        let mut compiler = Compiler::new("true");
-        let chunk = compiler.compile();
+        let chunk = compile("true;").unwrap();
 
         assert_eq!(chunk.code[0], OpCode::True as u8);
     }
 
     #[test]
     fn literal_false() {
-        let mut compiler = Compiler::new("false");
-        let chunk = compiler.compile();
+        let chunk = compile("false;").unwrap();
 
         assert_eq!(chunk.code[0], OpCode::False as u8);
     }
 
     #[test]
     fn literal_nil() {
-        let mut compiler = Compiler::new("nil");
-        let chunk = compiler.compile();
+        let chunk = compile("nil;").unwrap();
+
+        assert_eq!(chunk.code[0], OpCode::Nil as u8);
+    }
+
+    #[test]
+    fn print_statement() {
+        let chunk = compile("print 1;").unwrap();
+
+        assert_eq!(chunk.code[2], OpCode::Print as u8);
+    }
+
+    #[test]
+    fn expression_statement_is_popped() {
+        let chunk = compile("1; 2;").unwrap();
+
+        assert_eq!(chunk.code[2], OpCode::Pop as u8);
+        assert_eq!(chunk.code[5], OpCode::Pop as u8);
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        let result = compile("print 1");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn global_declaration() {
+        let chunk = compile("var answer = 42;").unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "answer");
+        assert_eq!(chunk.code[2], OpCode::DefineGlobal as u8);
+        assert_eq!(chunk.code[3], 0);
+    }
+
+    #[test]
+    fn global_assignment() {
+        let chunk = compile("answer = 42;").unwrap();
+
+        assert_eq!(chunk.code[2], OpCode::SetGlobal as u8);
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        let result = compile("var a; var b; a + b = 1;");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn local_variables() {
+        let chunk = compile("{ var a = 1; var b = a; }").unwrap();
+
+        assert_eq!(chunk.code[2], OpCode::GetLocal as u8);
+        assert_eq!(chunk.code[3], 1);
+        assert_eq!(chunk.code[4], OpCode::Pop as u8);
+        assert_eq!(chunk.code[5], OpCode::Pop as u8);
+    }
+
+    #[test]
+    fn local_in_own_initializer() {
+        let result = compile("{ var a = a; }");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn local_redeclaration() {
+        let result = compile("{ var a = 1; var a = 2; }");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn if_statement() {
+        let chunk = compile("if (true) print 1;").unwrap();
+
+        assert_eq!(chunk.code[1], OpCode::JumpIfFalse as u8);
+        assert_eq!(chunk.code[2..4], [0, 7]);
+        assert_eq!(chunk.code[8], OpCode::Jump as u8);
+    }
+
+    #[test]
+    fn while_statement() {
+        let chunk = compile("while (false) 1;").unwrap();
+
+        assert_eq!(chunk.code[1], OpCode::JumpIfFalse as u8);
+        assert_eq!(chunk.code[8], OpCode::Loop as u8);
+        assert_eq!(chunk.code[9..11], [0, 11]);
+    }
+
+    #[test]
+    fn logical_and() {
+        let chunk = compile("true and false;").unwrap();
+
+        assert_eq!(chunk.code[1], OpCode::JumpIfFalse as u8);
+        assert_eq!(chunk.code[4], OpCode::Pop as u8);
+        assert_eq!(chunk.code[5], OpCode::False as u8);
+    }
+
+    #[test]
+    fn logical_or() {
+        let chunk = compile("true or false;").unwrap();
+
+        assert_eq!(chunk.code[1], OpCode::JumpIfFalse as u8);
+        assert_eq!(chunk.code[4], OpCode::Jump as u8);
+        assert_eq!(chunk.code[7], OpCode::Pop as u8);
+    }
+
+    #[test]
+    fn function_declaration() {
+        let chunk = compile("fun add(a, b) { return a + b; } add(1, 2);").unwrap();
+
+        if let Value::Function(function) = &chunk.constants[1] {
+            assert_eq!(function.arity, 2);
+            assert_eq!(function.name.as_deref(), Some("add"));
+            assert_eq!(function.chunk.code[4], OpCode::Add as u8);
+            assert_eq!(function.chunk.code[5], OpCode::Return as u8);
+        } else {
+            panic!("Expected a function constant");
+        }
+
+        assert_eq!(chunk.code[10], OpCode::Call as u8);
+        assert_eq!(chunk.code[11], 2);
+    }
+
+    #[test]
+    fn top_level_return() {
+        let result = compile("return 1;");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn captured_local() {
+        let chunk =
+            compile("fun outer() { var x = 1; fun inner() { return x; } return inner; }").unwrap();
+
+        if let Value::Function(outer) = &chunk.constants[1] {
+            let code = &outer.chunk.code;
+            assert_eq!(code[2], OpCode::Closure as u8);
+            // is_local, index
+            assert_eq!(code[4..6], [1, 1]);
+
+            if let Value::Function(inner) = &outer.chunk.constants[code[3] as usize] {
+                assert_eq!(inner.upvalue_count, 1);
+                assert_eq!(inner.chunk.code[0], OpCode::GetUpvalue as u8);
+            } else {
+                panic!("Expected a function constant");
+            }
+        } else {
+            panic!("Expected a function constant");
+        }
+    }
+
+    #[test]
+    fn class_declaration() {
+        let chunk = compile("class Point {}").unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "Point");
+        assert_eq!(chunk.code[0], OpCode::Class as u8);
+        assert_eq!(chunk.code[2], OpCode::DefineGlobal as u8);
+    }
+
+    #[test]
+    fn property_access() {
+        let chunk = compile("point.x = point.y;").unwrap();
+
+        assert_eq!(chunk.code[4], OpCode::GetProperty as u8);
+        assert_eq!(chunk.code[6], OpCode::SetProperty as u8);
+    }
+
+    #[test]
+    fn method_invocation() {
+        let chunk = compile("class A { f() { return this; } } A().f(1);").unwrap();
+
+        if let Value::Function(method) = &chunk.constants[3] {
+            assert_eq!(method.chunk.code[0], OpCode::GetLocal as u8);
+            assert_eq!(method.chunk.code[1], 0);
+        } else {
+            panic!("Expected a function constant");
+        }
+
+        assert_eq!(chunk.code[8], OpCode::Method as u8);
+        assert_eq!(chunk.code[17], OpCode::Invoke as u8);
+        assert_eq!(chunk.code[19], 1);
+    }
+
+    #[test]
+    fn this_outside_class() {
+        let result = compile("print this;");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn initializer_return_value() {
+        let result = compile("class A { init() { return 1; } }");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn inheritance() {
+        let chunk = compile("class A {} class B < A { f() { return super.f; } }").unwrap();
 
+        assert!(chunk.code.contains(&(OpCode::Inherit as u8)));
+    }
+
+    #[test]
+    fn inherit_from_self() {
+        let result = compile("class A < A {}");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn super_without_superclass() {
+        let result = compile("class A { f() { super.f(); } }");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn super_outside_class() {
+        let result = compile("super.f();");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn list_literal() {
+        let chunk = compile("[1, 2, 3];").unwrap();
+
+        assert_eq!(chunk.code[6], OpCode::BuildList as u8);
+        assert_eq!(chunk.code[7], 3);
+    }
+
+    #[test]
+    fn index_assignment() {
+        let chunk = compile("var xs = [1]; xs[0] = 2; xs[0];").unwrap();
+
+        assert!(chunk.code.contains(&(OpCode::IndexSet as u8)));
+        assert!(chunk.code.contains(&(OpCode::IndexGet as u8)));
+    }
+
+    #[test]
+    fn unterminated_list() {
+        let result = compile("[1, 2;");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn map_literal() {
+        let chunk = compile(r#"var m = {"a": 1, "b": 2};"#).unwrap();
+
+        assert_eq!(chunk.code[8], OpCode::BuildMap as u8);
+        assert_eq!(chunk.code[9], 2);
+    }
+
+    #[test]
+    fn brace_statement_is_a_block() {
+        let chunk = compile("{ var a = 1; }").unwrap();
+
+        assert!(!chunk.code.contains(&(OpCode::BuildMap as u8)));
+    }
+
+    #[test]
+    fn map_entry_without_colon() {
+        let result = compile(r#"var m = {"a" 1};"#);
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn interpolation() {
+        let chunk = compile(r#""a ${1} b";"#).unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "a ");
+        assert_eq!(chunk.constants[2].as_str(), " b");
+        assert_eq!(chunk.code[4], OpCode::Stringify as u8);
+        assert_eq!(chunk.code[5], OpCode::Add as u8);
+        assert_eq!(chunk.code[8], OpCode::Add as u8);
+    }
+
+    #[test]
+    fn unterminated_interpolation() {
+        let result = compile(r#""a ${1 2}";"#);
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn interpolation_at_end_of_source() {
+        for source in [r#"print "a ${"#, r#"print "a ${1"#, r#"print "a ${1}"#] {
+            assert_eq!(compile(source).err(), Some(InterpretError::Compile));
+        }
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let chunk = compile(r#""\n\t\r\"\\\$\u{e9}\u{1F600}";"#).unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "\n\t\r\"\\$\u{e9}\u{1F600}");
+    }
+
+    #[test]
+    fn invalid_escape_sequences() {
+        for source in [
+            r#""\q";"#,
+            r#""\u{}";"#,
+            r#""\u{110000}";"#,
+            r#""\u{1234567}";"#,
+        ] {
+            assert_eq!(compile(source).err(), Some(InterpretError::Compile));
+        }
+    }
+
+    #[test]
+    fn break_pops_loop_locals() {
+        let chunk = compile("while (true) { var a = 1; break; }").unwrap();
+
+        // The local is popped before jumping past the loop
+        assert_eq!(chunk.code[7], OpCode::Pop as u8);
+        assert_eq!(chunk.code[8], OpCode::Jump as u8);
+        assert_eq!(chunk.code[9..11], [0, 5]);
+    }
+
+    #[test]
+    fn continue_jumps_to_increment() {
+        let chunk = compile("for (var i = 0; i < 1; i = i + 1) continue;").unwrap();
+
+        // From the increment, the end of the body and the continue
+        assert_eq!(
+            chunk
+                .code
+                .iter()
+                .filter(|&&byte| byte == OpCode::Loop as u8)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        for source in ["break;", "continue;", "while (true) { fun f() { break; } }"] {
+            assert_eq!(compile(source).err(), Some(InterpretError::Compile));
+        }
+    }
+
+    #[test]
+    fn try_catch() {
+        let chunk = compile("try { throw 1; } catch (e) { e; }").unwrap();
+
+        // Slot 1 is kept for a return value while a finally block runs
         assert_eq!(chunk.code[0], OpCode::Nil as u8);
+        assert_eq!(chunk.code[1], OpCode::PushHandler as u8);
+        assert_eq!(chunk.code[4], OpCode::PushHandler as u8);
+        assert_eq!(chunk.code[9], OpCode::Throw as u8);
+        // The exception is the catch block's first local
+        assert_eq!(chunk.code[15..17], [OpCode::GetLocal as u8, 2]);
+    }
+
+    #[test]
+    fn try_without_handlers() {
+        let result = compile("try {} print 1;");
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn break_out_of_try_removes_handlers() {
+        let chunk = compile("while (true) { try { break; } catch (e) {} }").unwrap();
+
+        assert_eq!(chunk.code[12..14], [OpCode::PopHandler as u8; 2]);
+        assert_eq!(chunk.code[14], OpCode::Jump as u8);
+    }
+
+    #[test]
+    fn return_from_try_keeps_value_below_handlers() {
+        let chunk = compile("fun f() { try { return 1; } finally {} }").unwrap();
+        if let Value::Function(function) = &chunk.constants[1] {
+            let code = &function.chunk.code;
+            assert_eq!(code[9..12], [OpCode::SetLocal as u8, 1, OpCode::Pop as u8]);
+            assert_eq!(code[12..14], [OpCode::PopHandler as u8; 2]);
+            assert_eq!(code[14], OpCode::Jump as u8);
+        } else {
+            panic!("Expected a function constant");
+        }
+    }
+
+    #[test]
+    fn import_as() {
+        let chunk = compile(r#"import "lib.rox" as lib;"#).unwrap();
+
+        assert_eq!(chunk.constants[0].as_str(), "lib.rox");
+        assert_eq!(chunk.code[0..2], [OpCode::Import as u8, 0]);
+        assert_eq!(chunk.code[2..4], [OpCode::DefineGlobal as u8, 1]);
+    }
+
+    #[test]
+    fn selective_import() {
+        let chunk = compile(r#"import "lib.rox" for a, b;"#).unwrap();
+
+        assert_eq!(chunk.code[2..4], [OpCode::ImportFrom as u8, 1]);
+        assert_eq!(chunk.code[4..6], [OpCode::DefineGlobal as u8, 1]);
+        assert_eq!(chunk.code[6..8], [OpCode::ImportFrom as u8, 2]);
+        assert_eq!(chunk.code[10], OpCode::Pop as u8);
+    }
+
+    #[test]
+    fn nested_import() {
+        let result = compile(r#"fun f() { import "lib.rox" as lib; }"#);
+
+        assert_eq!(result.err(), Some(InterpretError::Compile));
+    }
+
+    #[test]
+    fn float_constant() {
+        let chunk = compile("10.5;").unwrap();
+
+        assert!(matches!(chunk.constants[0], Value::Float(n) if n == 10.5));
+    }
+
+    #[test]
+    fn big_integer_constant() {
+        let chunk = compile("123456789012345678901234567890;").unwrap();
+
+        match chunk.constants[0] {
+            Value::BigInt(n) => assert_eq!(n.to_string(), "123456789012345678901234567890"),
+            _ => panic!("Expected a big integer constant"),
+        }
+    }
+
+    #[test]
+    fn exponent_is_right_associative_and_binds_tighter_than_unary() {
+        let chunk = compile("-2 ** 3 ** 2;").unwrap();
+
+        assert_eq!(
+            chunk.code[6..10],
+            [
+                OpCode::Power as u8,
+                OpCode::Power as u8,
+                OpCode::Negate as u8,
+                OpCode::Pop as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn bitwise_precedence() {
+        // Parsed as (1 | ((2 ^ (3 & (4 << 5)))) == 6
+        let chunk = compile("1 | 2 ^ 3 & 4 << 5 == 6;").unwrap();
+
+        assert_eq!(
+            chunk.code[10..14],
+            [
+                OpCode::ShiftLeft as u8,
+                OpCode::BitAnd as u8,
+                OpCode::BitXor as u8,
+                OpCode::BitOr as u8
+            ]
+        );
+        assert_eq!(chunk.code[16], OpCode::Equal as u8);
+    }
+
+    #[test]
+    fn compound_assignment_to_index_copies_operands() {
+        let chunk = compile("var a; a[0] += 1;").unwrap();
+
+        // a, 0, then copies of both for the read
+        assert_eq!(
+            chunk.code[7..16],
+            [
+                OpCode::Copy as u8,
+                1,
+                OpCode::Copy as u8,
+                1,
+                OpCode::IndexGet as u8,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Add as u8,
+                OpCode::IndexSet as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn postfix_increment_keeps_old_value() {
+        let chunk = compile("var a; a.b++;").unwrap();
+
+        assert_eq!(
+            chunk.code[5..17],
+            [
+                OpCode::Copy as u8,
+                0,
+                OpCode::GetProperty as u8,
+                2,
+                OpCode::Copy as u8,
+                0,
+                OpCode::Bury as u8,
+                2,
+                OpCode::Constant as u8,
+                3,
+                OpCode::Add as u8,
+                OpCode::SetProperty as u8
+            ]
+        );
+        assert_eq!(chunk.code[18..20], [OpCode::Pop as u8, OpCode::Pop as u8]);
+    }
+
+    #[test]
+    fn invalid_update_targets() {
+        for source in [
+            "1 += 2;",
+            "var a; var b; a + b -= 1;",
+            "var a; var b; (a or b) *= 2;",
+            "var a; ++a.b();",
+            "var a; ++a++;",
+            "class A { f() { this++; } }",
+        ] {
+            assert_eq!(compile(source).err(), Some(InterpretError::Compile));
+        }
+    }
+
+    #[test]
+    fn conditional() {
+        let chunk = compile("true ? 1 : 2;").unwrap();
+
+        assert_eq!(chunk.code[1..3], [OpCode::JumpIfFalse as u8, 0]);
+        assert_eq!(chunk.code[3], 6);
+        assert_eq!(chunk.code[7..10], [OpCode::Jump as u8, 0, 3]);
+        assert_eq!(chunk.code[10], OpCode::Pop as u8);
+    }
+
+    #[test]
+    fn conditional_errors() {
+        for source in [
+            "true ? 1;",
+            "true ? 1 : ;",
+            "var a; var b; true ? a : b = 1;",
+        ] {
+            assert_eq!(compile(source).err(), Some(InterpretError::Compile));
+        }
     }
 }
@@ -1,9 +1,14 @@
+mod bigint;
 mod bytecode;
 mod compiler;
+mod memory;
+mod natives;
+mod object;
 mod scanner;
 mod vm;
 
 use std::io::Write;
+use std::path::Path;
 use std::{env, fs, io, process};
 use vm::{InterpretError, VM};
 
@@ -28,7 +33,7 @@ fn repl(mut vm: VM) {
         io::stdout().flush().expect("Couldn't flush stdout");
 
         let mut line = String::new();
-        if let io::Result::Ok(_) = io::stdin().read_line(&mut line) {
+        if io::stdin().read_line(&mut line).is_ok() {
             let result = vm.interpret_source(&line);
 
             if let Ok(value) = result {
@@ -42,7 +47,7 @@ fn repl(mut vm: VM) {
 
 fn run_file(mut vm: VM, path: &str) {
     let source = fs::read_to_string(path).unwrap();
-    let result = vm.interpret_source(&source);
+    let result = vm.interpret_file(Path::new(path), &source);
 
     match result {
         Err(InterpretError::Compile) => process::exit(65),
@@ -0,0 +1,309 @@
+use crate::bytecode::Value;
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::ptr::NonNull;
+
+// Heap size that triggers the first collection
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+// How much the threshold grows relative to the live heap after a collection
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Implemented by every object that can live on the garbage-collected heap.
+/// `trace` must mark every handle the object holds so that it survives collection.
+pub trait Trace {
+    fn trace(&self, marker: &mut Marker);
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    value: T,
+}
+
+/// A handle to an object owned by a `Heap`.
+///
+/// Handles are only valid while the object is reachable from the VM's roots; the
+/// collector frees everything else.
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: ?Sized> Gc<T> {
+    pub fn ptr_eq(a: Gc<T>, b: Gc<T>) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    fn header(&self) -> &GcBox<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Gc<T> {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> std::ops::Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.header().value
+    }
+}
+
+// Handles compare and hash by identity, which for interned strings is the same
+// as comparing their contents
+impl<T: ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Gc<T>) -> bool {
+        Gc::ptr_eq(*self, *other)
+    }
+}
+
+impl<T: ?Sized> Eq for Gc<T> {}
+
+impl<T: ?Sized> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.as_ptr().cast::<()>().hash(state);
+    }
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + std::fmt::Display> std::fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// Collects the gray set while tracing from the roots.
+pub struct Marker {
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+}
+
+impl Marker {
+    pub fn new() -> Marker {
+        Marker { gray: Vec::new() }
+    }
+
+    pub fn mark<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        if object.header().marked.replace(true) {
+            return;
+        }
+
+        self.gray.push(object.ptr);
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        match *value {
+            Value::BigInt(n) => self.mark(n),
+            Value::Str(string) => self.mark(string),
+            Value::Function(function) => self.mark(function),
+            Value::Closure(closure) => self.mark(closure),
+            Value::Class(class) => self.mark(class),
+            Value::Instance(instance) => self.mark(instance),
+            Value::BoundMethod(bound) => self.mark(bound),
+            Value::Native(native) => self.mark(native),
+            Value::List(list) => self.mark(list),
+            Value::Map(map) => self.mark(map),
+            Value::Module(module) => self.mark(module),
+            Value::Int(_) | Value::Float(_) | Value::Bool(_) | Value::Nil => (),
+        }
+    }
+}
+
+// Entry in the intern table, looked up by the string's contents
+struct Interned(Gc<String>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Interned) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    // Weak: strings only reachable from here are freed, and dropped from the table
+    strings: HashSet<Interned>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Collect on every allocation to shake out rooting bugs
+    stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            strings: HashSet::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress: std::env::var_os("ROX_STRESS_GC").is_some(),
+        }
+    }
+
+    /// Moves `value` onto the heap. This never collects; callers that hold roots
+    /// decide when to collect through `should_collect`.
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let boxed = Box::new(GcBox {
+            marked: Cell::new(false),
+            value,
+        });
+
+        self.bytes_allocated += std::mem::size_of_val(&*boxed);
+
+        let ptr = NonNull::from(Box::leak(boxed));
+        self.objects.push(ptr);
+
+        Gc { ptr }
+    }
+
+    /// Returns the single heap string with these contents, allocating it if needed.
+    pub fn intern(&mut self, string: &str) -> Gc<String> {
+        match self.strings.get(string) {
+            Some(interned) => interned.0,
+            None => self.intern_owned(string.to_string()),
+        }
+    }
+
+    /// Like `intern`, but takes ownership so a new string needn't be copied.
+    pub fn intern_owned(&mut self, string: String) -> Gc<String> {
+        if let Some(interned) = self.strings.get(string.as_str()) {
+            return interned.0;
+        }
+
+        let interned = self.alloc(string);
+        self.strings.insert(Interned(interned));
+        interned
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Traces everything reachable from the objects already marked in `marker`
+    /// and frees the rest.
+    pub fn collect(&mut self, mut marker: Marker) {
+        while let Some(object) = marker.gray.pop() {
+            unsafe { object.as_ref() }.value.trace(&mut marker);
+        }
+
+        self.strings.retain(|string| string.0.header().marked.get());
+        self.sweep();
+        self.next_gc = self
+            .bytes_allocated
+            .saturating_mul(GC_HEAP_GROW_FACTOR)
+            .max(INITIAL_GC_THRESHOLD);
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+
+        self.objects.retain(|object| {
+            let header = unsafe { object.as_ref() };
+            if header.marked.replace(false) {
+                return true;
+            }
+
+            freed += std::mem::size_of_val(header);
+            drop(unsafe { Box::from_raw(object.as_ptr()) });
+            false
+        });
+
+        self.bytes_allocated -= freed;
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            drop(unsafe { Box::from_raw(object.as_ptr()) });
+        }
+    }
+}
+
+impl Trace for String {
+    fn trace(&self, _marker: &mut Marker) {}
+}
+
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, marker: &mut Marker) {
+        self.borrow().trace(marker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmarked_objects_are_freed() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc(String::from("kept"));
+        heap.alloc(String::from("garbage"));
+
+        let mut marker = Marker::new();
+        marker.mark(kept);
+        heap.collect(marker);
+
+        assert_eq!(heap.objects.len(), 1);
+        assert_eq!(*kept, "kept");
+        assert!(!kept.header().marked.get());
+    }
+
+    #[test]
+    fn references_are_traced() {
+        let mut heap = Heap::new();
+        let string = heap.alloc(String::from("field"));
+        let upvalue = heap.alloc(RefCell::new(crate::object::Upvalue::Closed(Value::Str(
+            string,
+        ))));
+
+        let mut marker = Marker::new();
+        marker.mark(upvalue);
+        heap.collect(marker);
+
+        assert_eq!(heap.objects.len(), 2);
+    }
+
+    #[test]
+    fn equal_strings_are_interned_once() {
+        let mut heap = Heap::new();
+        let a = heap.intern("name");
+        let b = heap.intern_owned(String::from("name"));
+
+        assert!(Gc::ptr_eq(a, b));
+        assert_eq!(heap.objects.len(), 1);
+    }
+
+    #[test]
+    fn unreachable_interned_strings_are_forgotten() {
+        let mut heap = Heap::new();
+        heap.intern("garbage");
+        heap.collect(Marker::new());
+
+        assert!(heap.strings.is_empty());
+        assert!(heap.objects.is_empty());
+    }
+}
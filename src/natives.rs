@@ -0,0 +1,48 @@
+use crate::bigint::BigInt;
+use crate::bytecode::Value;
+use crate::vm::VM;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn define_all(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("int", 1, int);
+    vm.define_native("float", 1, float);
+}
+
+fn clock(_vm: &mut VM, _arguments: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|error| error.to_string())?;
+
+    Ok(Value::Float(now.as_secs_f64()))
+}
+
+/// Converts a number or numeric string to an integer, truncating floats toward zero.
+fn int(vm: &mut VM, arguments: &[Value]) -> Result<Value, String> {
+    let converted = match arguments[0] {
+        Value::Int(_) | Value::BigInt(_) => return Ok(arguments[0]),
+        Value::Float(n) => BigInt::from_f64(n.trunc()),
+        Value::Str(s) => BigInt::parse(s.trim()),
+        _ => None,
+    };
+
+    match converted {
+        Some(n) => vm
+            .integer(n)
+            .map_err(|_| String::from("Integer is too large.")),
+        None => Err(format!("Can't convert {} to an integer.", arguments[0])),
+    }
+}
+
+/// Converts a number or numeric string to a float.
+fn float(_vm: &mut VM, arguments: &[Value]) -> Result<Value, String> {
+    let converted = match arguments[0] {
+        Value::Int(_) | Value::BigInt(_) | Value::Float(_) => Some(arguments[0].as_float()),
+        Value::Str(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+
+    converted
+        .map(Value::Float)
+        .ok_or_else(|| format!("Can't convert {} to a float.", arguments[0]))
+}
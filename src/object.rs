@@ -0,0 +1,342 @@
+use crate::bytecode::{Chunk, Value};
+use crate::memory::{Gc, Marker, Trace};
+use crate::vm::VM;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<String>,
+    // Whose globals the function's code reads and writes
+    pub module: Gc<Module>,
+}
+
+impl Function {
+    pub fn new(name: Option<String>, module: Gc<Module>) -> Function {
+        Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(Vec::new(), Vec::new(), Vec::new()),
+            name,
+            module,
+        }
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark(self.module);
+        for constant in &self.chunk.constants {
+            marker.mark_value(constant);
+        }
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Gc<Function>) -> Closure {
+        Closure {
+            upvalues: Vec::with_capacity(function.upvalue_count),
+            function,
+        }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark(self.function);
+        for upvalue in &self.upvalues {
+            marker.mark(*upvalue);
+        }
+    }
+}
+
+impl std::fmt::Display for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    // Still lives on the VM stack at this slot
+    Open(usize),
+    // Moved off the stack when its enclosing frame returned
+    Closed(Value),
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, marker: &mut Marker) {
+        if let Upvalue::Closed(value) = self {
+            marker.mark_value(value);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<Gc<String>, Gc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, marker: &mut Marker) {
+        for (name, method) in self.methods.borrow().iter() {
+            marker.mark(*name);
+            marker.mark(*method);
+        }
+    }
+}
+
+impl std::fmt::Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Gc<Class>,
+    pub fields: RefCell<HashMap<Gc<String>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark(self.class);
+        for (name, field) in self.fields.borrow().iter() {
+            marker.mark(*name);
+            marker.mark_value(field);
+        }
+    }
+}
+
+impl std::fmt::Display for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Gc<Closure>) -> BoundMethod {
+        BoundMethod { receiver, method }
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, marker: &mut Marker) {
+        marker.mark_value(&self.receiver);
+        marker.mark(self.method);
+    }
+}
+
+impl std::fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+#[derive(Debug)]
+pub struct List {
+    pub elements: RefCell<Vec<Value>>,
+    // Set while the list is being printed, so a list inside itself prints as `[...]`
+    printing: Cell<bool>,
+}
+
+impl List {
+    pub fn new(elements: Vec<Value>) -> List {
+        List {
+            elements: RefCell::new(elements),
+            printing: Cell::new(false),
+        }
+    }
+
+    fn write_elements(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, element) in self.elements.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", element)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl Trace for List {
+    fn trace(&self, marker: &mut Marker) {
+        for element in self.elements.borrow().iter() {
+            marker.mark_value(element);
+        }
+    }
+}
+
+impl std::fmt::Display for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.printing.replace(true) {
+            return write!(f, "[...]");
+        }
+
+        let result = self.write_elements(f);
+        self.printing.set(false);
+        result
+    }
+}
+
+#[derive(Debug)]
+pub struct Map {
+    pub entries: RefCell<HashMap<Value, Value>>,
+    // Set while the map is being printed, so a map inside itself prints as `{...}`
+    printing: Cell<bool>,
+}
+
+impl Map {
+    pub fn new(entries: HashMap<Value, Value>) -> Map {
+        Map {
+            entries: RefCell::new(entries),
+            printing: Cell::new(false),
+        }
+    }
+
+    fn write_entries(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.entries.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", key, value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl Trace for Map {
+    fn trace(&self, marker: &mut Marker) {
+        for (key, value) in self.entries.borrow().iter() {
+            marker.mark_value(key);
+            marker.mark_value(value);
+        }
+    }
+}
+
+impl std::fmt::Display for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.printing.replace(true) {
+            return write!(f, "{{...}}");
+        }
+
+        let result = self.write_entries(f);
+        self.printing.set(false);
+        result
+    }
+}
+
+/// A source file's global namespace. Every module's globals are separate; built-ins
+/// are shared by all of them.
+#[derive(Debug)]
+pub struct Module {
+    // Canonical path of the file, or empty for code not read from a file
+    pub path: PathBuf,
+    pub globals: RefCell<HashMap<Gc<String>, Value>>,
+    // Unset while the module's top-level code is still running
+    pub loaded: Cell<bool>,
+}
+
+impl Module {
+    pub fn new(path: PathBuf) -> Module {
+        Module {
+            path,
+            globals: RefCell::new(HashMap::new()),
+            loaded: Cell::new(false),
+        }
+    }
+}
+
+impl Trace for Module {
+    fn trace(&self, marker: &mut Marker) {
+        for (name, value) in self.globals.borrow().iter() {
+            marker.mark(*name);
+            marker.mark_value(value);
+        }
+    }
+}
+
+impl std::fmt::Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.as_os_str().is_empty() {
+            write!(f, "script")
+        } else {
+            write!(f, "{}", self.path.display())
+        }
+    }
+}
+
+pub type NativeFn = fn(vm: &mut VM, arguments: &[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: String, arity: usize, function: NativeFn) -> Native {
+        Native {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl Trace for Native {
+    fn trace(&self, _marker: &mut Marker) {}
+}
+
+impl std::fmt::Display for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
@@ -4,15 +4,18 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: u32,
+    // Brace depth inside each `${` being scanned, innermost last
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn new(source: &str) -> Scanner<'_> {
         Scanner {
             source,
             start: 0,
             current: 0,
             line: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -35,18 +38,77 @@ impl<'a> Scanner<'a> {
             return self.number();
         }
 
-        return match c {
+        match c {
             '(' => self.make_token(TokenKind::LeftParen),
             ')' => self.make_token(TokenKind::RightParen),
-            '{' => self.make_token(TokenKind::LeftBrace),
-            '}' => self.make_token(TokenKind::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenKind::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                // Closes an interpolated expression, so the string carries on
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenKind::RightBrace)
+                }
+                None => self.make_token(TokenKind::RightBrace),
+            },
+            '[' => self.make_token(TokenKind::LeftBracket),
+            ']' => self.make_token(TokenKind::RightBracket),
             ';' => self.make_token(TokenKind::Semicolon),
+            ':' => self.make_token(TokenKind::Colon),
+            '?' => self.make_token(TokenKind::Question),
             ',' => self.make_token(TokenKind::Comma),
             '.' => self.make_token(TokenKind::Dot),
-            '-' => self.make_token(TokenKind::Minus),
-            '+' => self.make_token(TokenKind::Plus),
-            '/' => self.make_token(TokenKind::Slash),
-            '*' => self.make_token(TokenKind::Star),
+            '-' => {
+                let kind = if self.matches("-") {
+                    TokenKind::MinusMinus
+                } else if self.matches("=") {
+                    TokenKind::MinusEqual
+                } else {
+                    TokenKind::Minus
+                };
+                self.make_token(kind)
+            }
+            '+' => {
+                let kind = if self.matches("+") {
+                    TokenKind::PlusPlus
+                } else if self.matches("=") {
+                    TokenKind::PlusEqual
+                } else {
+                    TokenKind::Plus
+                };
+                self.make_token(kind)
+            }
+            '/' => {
+                let kind = if self.matches("=") {
+                    TokenKind::SlashEqual
+                } else {
+                    TokenKind::Slash
+                };
+                self.make_token(kind)
+            }
+            '*' => {
+                let kind = if self.matches("*") {
+                    TokenKind::StarStar
+                } else if self.matches("=") {
+                    TokenKind::StarEqual
+                } else {
+                    TokenKind::Star
+                };
+                self.make_token(kind)
+            }
+            '%' => self.make_token(TokenKind::Percent),
+            '&' => self.make_token(TokenKind::Ampersand),
+            '|' => self.make_token(TokenKind::Pipe),
+            '^' => self.make_token(TokenKind::Caret),
+            '~' => self.make_token(TokenKind::Tilde),
             '!' => {
                 let kind = if self.matches("=") {
                     TokenKind::BangEqual
@@ -66,6 +128,8 @@ impl<'a> Scanner<'a> {
             '<' => {
                 let kind = if self.matches("=") {
                     TokenKind::LessEqual
+                } else if self.matches("<") {
+                    TokenKind::LessLess
                 } else {
                     TokenKind::Less
                 };
@@ -74,6 +138,8 @@ impl<'a> Scanner<'a> {
             '>' => {
                 let kind = if self.matches("=") {
                     TokenKind::GreaterEqual
+                } else if self.matches(">") {
+                    TokenKind::GreaterGreater
                 } else {
                     TokenKind::Greater
                 };
@@ -81,7 +147,7 @@ impl<'a> Scanner<'a> {
             }
             '"' => self.string(),
             _ => self.make_error_token("Unexpected character"),
-        };
+        }
     }
 
     fn make_token(&self, kind: TokenKind) -> Token<'a> {
@@ -97,33 +163,38 @@ impl<'a> Scanner<'a> {
         self.current == self.source.len()
     }
 
-    fn peek(&self) -> &str {
-        if self.is_at_end() {
-            "\0"
-        } else {
-            &self.source[self.current..self.current + 1]
+    // Characters are sliced out whole so that non-ASCII source doesn't split a code point
+    fn char_at(&self, index: usize) -> &str {
+        match self.source[index..].chars().next() {
+            Some(c) => &self.source[index..index + c.len_utf8()],
+            None => "\0",
         }
     }
 
+    fn peek(&self) -> &str {
+        self.char_at(self.current)
+    }
+
     fn peek_next(&self) -> &str {
-        if self.source.len() == self.current + 1 {
+        if self.is_at_end() {
             "\0"
         } else {
-            &self.source[self.current + 1..self.current + 2]
+            self.char_at(self.current + self.peek().len())
         }
     }
 
     fn advance(&mut self) -> &str {
-        self.current += 1;
-        &self.source[self.current - 1..self.current]
+        let start = self.current;
+        self.current += self.peek().len();
+        &self.source[start..self.current]
     }
 
     fn matches(&mut self, expected: &str) -> bool {
-        if self.start == self.source.len() {
+        if self.is_at_end() {
             return false;
         }
 
-        if &self.source[self.current..self.current + 1] != expected {
+        if self.peek() != expected {
             return false;
         }
 
@@ -145,13 +216,9 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == "/" {
-                        while self.peek() != "\n" && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == "/" => {
+                    while self.peek() != "\n" && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -159,10 +226,29 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    /// Scans the rest of a string literal, or of the part up to the next `${`.
+    /// The lexeme starts at the opening '"', or at the '}' ending an interpolation.
     fn string(&mut self) -> Token<'a> {
         while self.peek() != "\"" && !self.is_at_end() {
-            if self.peek() == "\n" {
-                self.line += 1;
+            match self.peek() {
+                "\n" => self.line += 1,
+                // Escapes are checked by the compiler; here they only mustn't end the string
+                "\\" => {
+                    self.advance();
+                    if self.is_at_end() {
+                        break;
+                    }
+                    if self.peek() == "\n" {
+                        self.line += 1;
+                    }
+                }
+                "$" if self.peek_next() == "{" => {
+                    self.advance();
+                    self.advance();
+                    self.interpolations.push(0);
+                    return self.make_token(TokenKind::Interpolation);
+                }
+                _ => (),
             }
             self.advance();
         }
@@ -203,19 +289,28 @@ impl<'a> Scanner<'a> {
 
         let kind = match lexeme {
             "and" => TokenKind::And,
+            "as" => TokenKind::As,
+            "break" => TokenKind::Break,
+            "catch" => TokenKind::Catch,
             "class" => TokenKind::Class,
+            "continue" => TokenKind::Continue,
             "else" => TokenKind::Else,
             "false" => TokenKind::False,
-            "for" => TokenKind::False,
+            "finally" => TokenKind::Finally,
+            "for" => TokenKind::For,
             "fun" => TokenKind::Fun,
             "if" => TokenKind::If,
+            "import" => TokenKind::Import,
+            "in" => TokenKind::In,
             "nil" => TokenKind::Nil,
             "or" => TokenKind::Or,
             "print" => TokenKind::Print,
             "return" => TokenKind::Return,
             "super" => TokenKind::Super,
             "this" => TokenKind::This,
+            "throw" => TokenKind::Throw,
             "true" => TokenKind::True,
+            "try" => TokenKind::Try,
             "var" => TokenKind::Var,
             "while" => TokenKind::While,
             _ => TokenKind::Identifier,
@@ -238,38 +333,67 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
+    MinusEqual,
+    MinusMinus,
     Plus,
+    PlusEqual,
+    PlusPlus,
     Semicolon,
+    Colon,
+    Question,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    StarStar,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
     Identifier,
     String,
+    // A string segment followed by `${`
+    Interpolation,
     Number,
     And,
+    As,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
     Error,
@@ -333,4 +457,138 @@ mod tests {
         let mut scanner = Scanner::new("\n.");
         assert_eq!(scanner.next(), Token::new(TokenKind::Dot, ".", 2));
     }
+
+    #[test]
+    fn for_keyword_is_parsed() {
+        let mut scanner = Scanner::new("for");
+        assert_eq!(scanner.next(), Token::new(TokenKind::For, "for", 1));
+    }
+
+    #[test]
+    fn brackets_are_parsed() {
+        let mut scanner = Scanner::new("[]");
+        assert_eq!(scanner.next(), Token::new(TokenKind::LeftBracket, "[", 1));
+        assert_eq!(scanner.next(), Token::new(TokenKind::RightBracket, "]", 1));
+    }
+
+    #[test]
+    fn colon_and_in_are_parsed() {
+        let mut scanner = Scanner::new(": in");
+        assert_eq!(scanner.next(), Token::new(TokenKind::Colon, ":", 1));
+        assert_eq!(scanner.next(), Token::new(TokenKind::In, "in", 1));
+    }
+
+    #[test]
+    fn interpolation_is_split_into_segments() {
+        let mut scanner = Scanner::new("\"a ${ {}\n} b ${c} d\"");
+        assert_eq!(
+            scanner.next(),
+            Token::new(TokenKind::Interpolation, "\"a ${", 1)
+        );
+        assert_eq!(scanner.next(), Token::new(TokenKind::LeftBrace, "{", 1));
+        assert_eq!(scanner.next(), Token::new(TokenKind::RightBrace, "}", 1));
+        assert_eq!(
+            scanner.next(),
+            Token::new(TokenKind::Interpolation, "} b ${", 2)
+        );
+        assert_eq!(scanner.next(), Token::new(TokenKind::Identifier, "c", 2));
+        assert_eq!(scanner.next(), Token::new(TokenKind::String, "} d\"", 2));
+        assert_eq!(scanner.next(), Token::new(TokenKind::End, "", 2));
+    }
+
+    #[test]
+    fn escaped_quote_does_not_end_string() {
+        let mut scanner = Scanner::new(r#""a\"b\\" c"#);
+        assert_eq!(
+            scanner.next(),
+            Token::new(TokenKind::String, r#""a\"b\\""#, 1)
+        );
+        assert_eq!(scanner.next(), Token::new(TokenKind::Identifier, "c", 1));
+    }
+
+    #[test]
+    fn non_ascii_strings_are_parsed() {
+        let mut scanner = Scanner::new("\"héllo\" é");
+        assert_eq!(
+            scanner.next(),
+            Token::new(TokenKind::String, "\"héllo\"", 1)
+        );
+        assert_eq!(scanner.next().kind, TokenKind::Error);
+    }
+
+    #[test]
+    fn loop_control_keywords_are_parsed() {
+        let mut scanner = Scanner::new("break continue");
+        assert_eq!(scanner.next(), Token::new(TokenKind::Break, "break", 1));
+        assert_eq!(
+            scanner.next(),
+            Token::new(TokenKind::Continue, "continue", 1)
+        );
+    }
+
+    #[test]
+    fn exception_keywords_are_parsed() {
+        let mut scanner = Scanner::new("try catch finally throw");
+        assert_eq!(scanner.next().kind, TokenKind::Try);
+        assert_eq!(scanner.next().kind, TokenKind::Catch);
+        assert_eq!(scanner.next().kind, TokenKind::Finally);
+        assert_eq!(scanner.next().kind, TokenKind::Throw);
+    }
+
+    #[test]
+    fn import_keywords_are_parsed() {
+        let mut scanner = Scanner::new("import as");
+        assert_eq!(scanner.next(), Token::new(TokenKind::Import, "import", 1));
+        assert_eq!(scanner.next(), Token::new(TokenKind::As, "as", 1));
+    }
+
+    #[test]
+    fn long_numbers_are_one_token() {
+        let digits = "1".repeat(100);
+        let mut scanner = Scanner::new(&digits);
+        assert_eq!(scanner.next(), Token::new(TokenKind::Number, &digits, 1));
+    }
+
+    #[test]
+    fn arithmetic_and_bitwise_operators_are_parsed() {
+        let mut scanner = Scanner::new("** % & | ^ ~ << >> * <");
+        for (kind, lexeme) in [
+            (TokenKind::StarStar, "**"),
+            (TokenKind::Percent, "%"),
+            (TokenKind::Ampersand, "&"),
+            (TokenKind::Pipe, "|"),
+            (TokenKind::Caret, "^"),
+            (TokenKind::Tilde, "~"),
+            (TokenKind::LessLess, "<<"),
+            (TokenKind::GreaterGreater, ">>"),
+            (TokenKind::Star, "*"),
+            (TokenKind::Less, "<"),
+        ] {
+            assert_eq!(scanner.next(), Token::new(kind, lexeme, 1));
+        }
+    }
+
+    #[test]
+    fn assignment_operators_are_parsed() {
+        let mut scanner = Scanner::new("+= -= *= /= ++ -- **=");
+        for (kind, lexeme) in [
+            (TokenKind::PlusEqual, "+="),
+            (TokenKind::MinusEqual, "-="),
+            (TokenKind::StarEqual, "*="),
+            (TokenKind::SlashEqual, "/="),
+            (TokenKind::PlusPlus, "++"),
+            (TokenKind::MinusMinus, "--"),
+            (TokenKind::StarStar, "**"),
+            (TokenKind::Equal, "="),
+        ] {
+            assert_eq!(scanner.next(), Token::new(kind, lexeme, 1));
+        }
+    }
+
+    #[test]
+    fn conditional_operator_is_parsed() {
+        let mut scanner = Scanner::new("?:");
+        assert_eq!(scanner.next(), Token::new(TokenKind::Question, "?", 1));
+        assert_eq!(scanner.next(), Token::new(TokenKind::Colon, ":", 1));
+    }
 }
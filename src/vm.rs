@@ -1,9 +1,63 @@
-use crate::bytecode::{Chunk, OpCode, Value};
+use crate::bigint::BigInt;
+use crate::bytecode::{OpCode, Value};
 use crate::compiler::Compiler;
+use crate::memory::{Gc, Heap, Marker, Trace};
+use crate::natives;
+use crate::object::{
+    BoundMethod, Class, Closure, Function, Instance, List, Map, Module, Native, NativeFn, Upvalue,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FRAMES_MAX: usize = 64;
+// Integer results are limited to this many bits, since each bit costs time as well
+// as memory
+const INTEGER_BITS_MAX: u64 = 1 << 18;
 
 pub struct VM {
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    // Globals visible from every module, looked up when a module doesn't define a name
+    builtins: HashMap<Gc<String>, Value>,
+    // Module that code passed to `interpret_source` runs in
+    main: Gc<Module>,
+    // Imported modules by canonical path
+    modules: HashMap<PathBuf, Gc<Module>>,
+    // Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<Gc<RefCell<Upvalue>>>,
+    heap: Heap,
+    // Looked up on every class call, so interned once up front
+    init_string: Gc<String>,
+    // Class of the values built-in runtime errors are thrown as
+    error_class: Gc<Class>,
+    message_string: Gc<String>,
+    line_string: Gc<String>,
+    // Set when an instruction fails, until a handler catches it or it's reported
+    exception: Option<Exception>,
+}
+
+struct CallFrame {
+    closure: Gc<Closure>,
+    ip: usize,
+    // Index of the stack slot holding the callee; its locals follow
+    slot_base: usize,
+    // Enclosing `try` blocks of the code being run, innermost last
+    handlers: Vec<Handler>,
+}
+
+struct Handler {
+    catch_ip: usize,
+    // Stack height to unwind to before pushing the exception
+    stack_len: usize,
+}
+
+enum Exception {
+    Thrown(Value),
+    // Built-in errors become an `Error` instance only once a handler is found, since
+    // they can be raised while objects are borrowed or not yet rooted
+    Error { message: String, line: u32 },
 }
 
 #[derive(Debug, PartialEq)]
@@ -25,92 +79,298 @@ impl std::error::Error for InterpretError {}
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            ip: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let error_class = heap.alloc(Class::new(String::from("Error")));
+        let message_string = heap.intern("message");
+        let line_string = heap.intern("line");
+        let main = heap.alloc(Module::new(PathBuf::new()));
+
+        let mut vm = VM {
+            frames: Vec::new(),
             stack: Vec::new(),
-        }
+            builtins: HashMap::new(),
+            main,
+            modules: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            init_string,
+            error_class,
+            message_string,
+            line_string,
+            exception: None,
+        };
+
+        let error_name = vm.intern(String::from("Error"));
+        vm.builtins.insert(error_name, Value::Class(error_class));
+
+        natives::define_all(&mut vm);
+        vm
+    }
+
+    /// Exposes a Rust function to scripts as a global named `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.alloc(Native::new(name.to_string(), arity, function));
+        self.stack.push(Value::Native(native));
+        let name = self.intern(name.to_string());
+        self.builtins.insert(name, Value::Native(native));
+        self.stack.pop();
+    }
+
+    /// Runs `source` read from the file at `path` as the main module, so that its
+    /// imports are resolved relative to it.
+    pub fn interpret_file(&mut self, path: &Path, source: &str) -> Result<Value, InterpretError> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.main = self.alloc(Module::new(path.clone()));
+        self.modules.insert(path, self.main);
+        self.interpret_source(source)
     }
 
     pub fn interpret_source(&mut self, source: &str) -> Result<Value, InterpretError> {
-        let mut compiler = Compiler::new(source);
-        let chunk = compiler.compile();
-        self.run(&chunk)
+        let mut compiler = Compiler::new(source, &mut self.heap, self.main);
+        let function = compiler.compile()?;
+        self.interpret(function)
+    }
+
+    fn interpret(&mut self, function: Gc<Function>) -> Result<Value, InterpretError> {
+        // Keep the function reachable while its closure is allocated
+        self.stack.push(Value::Function(function));
+        let closure = self.alloc(Closure::new(function));
+        self.stack.pop();
+
+        self.stack.push(Value::Closure(closure));
+        self.call(closure, 0)?;
+        self.run()
+    }
+
+    /// Allocates on the VM's heap, collecting first if the heap has grown enough.
+    /// The new object is treated as a root so that anything it references survives.
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let object = self.heap.alloc(value);
+        self.collect_if_needed(object);
+        object
+    }
+
+    /// Returns the interned copy of `string`, which may trigger a collection like `alloc`.
+    pub fn intern(&mut self, string: String) -> Gc<String> {
+        let interned = self.heap.intern_owned(string);
+        self.collect_if_needed(interned);
+        interned
+    }
+
+    fn collect_if_needed<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        if self.heap.should_collect() {
+            let mut marker = Marker::new();
+            marker.mark(object);
+            self.collect_garbage(marker);
+        }
     }
 
-    fn run(&mut self, chunk: &Chunk) -> Result<Value, InterpretError> {
-        // Reset the instruction pointer for each run
-        self.ip = 0;
+    fn collect_garbage(&mut self, mut marker: Marker) {
+        for value in &self.stack {
+            marker.mark_value(value);
+        }
+
+        for (name, value) in &self.builtins {
+            marker.mark(*name);
+            marker.mark_value(value);
+        }
+
+        marker.mark(self.main);
+        for module in self.modules.values() {
+            marker.mark(*module);
+        }
+
+        marker.mark(self.init_string);
+        marker.mark(self.error_class);
+        marker.mark(self.message_string);
+        marker.mark(self.line_string);
+
+        if let Some(Exception::Thrown(value)) = &self.exception {
+            marker.mark_value(value);
+        }
+
+        for frame in &self.frames {
+            marker.mark(frame.closure);
+        }
+
+        for upvalue in &self.open_upvalues {
+            marker.mark(*upvalue);
+        }
 
-        println!("{:?}", self.stack);
-        chunk.disassemble("test");
+        self.heap.collect(marker);
+    }
+
+    fn run(&mut self) -> Result<Value, InterpretError> {
+        loop {
+            match self.execute() {
+                Err(InterpretError::Runtime) => {
+                    if !self.unwind() {
+                        self.report_exception();
+                        return Err(InterpretError::Runtime);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
 
+    // Runs until the script returns or an exception is raised
+    fn execute(&mut self) -> Result<Value, InterpretError> {
+        // Integers stay integers, growing into big integers on overflow; otherwise
+        // both operands are promoted to floats
         macro_rules! binop_float {
-			($op:tt) => {
-				{
-                    if !self.peek(0).is_float() || !self.peek(1).is_float() {
-                        self.runtime_error(chunk, "Operands must be a number");
+            ($op:tt, $checked:ident) => {
+                {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be a number");
                         return Err(InterpretError::Runtime);
                     }
-					let b = self.stack.pop().unwrap().as_float();
-					let a = self.stack.pop().unwrap().as_float();
-                    self.stack.push(Value::Float(a $op b));
-				};
-			}
-		}
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    let result = match (a, b) {
+                        (Value::Int(a), Value::Int(b)) => match a.$checked(b) {
+                            Some(result) => Value::Int(result),
+                            None => self.integer(&BigInt::from(a) $op &BigInt::from(b))?,
+                        },
+                        (a, b) if a.is_integer() && b.is_integer() => {
+                            self.integer(&a.as_bigint() $op &b.as_bigint())?
+                        }
+                        (a, b) => Value::Float(a.as_float() $op b.as_float()),
+                    };
+                    self.stack.push(result);
+                }
+            }
+        }
 
         macro_rules! binop_bool {
             ($op:tt) => {
                 {
-                    if !self.peek(0).is_float() || !self.peek(1).is_float() {
-                        self.runtime_error(chunk, "Operands must be a number");
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be a number");
+                        return Err(InterpretError::Runtime);
+                    }
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    let result = match (a, b) {
+                        (Value::Int(a), Value::Int(b)) => a $op b,
+                        (a, b) if a.is_integer() && b.is_integer() => {
+                            a.as_bigint() $op b.as_bigint()
+                        }
+                        (a, b) => a.as_float() $op b.as_float(),
+                    };
+                    self.stack.push(Value::Bool(result));
+                }
+            }
+        }
+
+        macro_rules! binop_int {
+            ($op:tt) => {
+                {
+                    if !self.peek(0).is_integer() || !self.peek(1).is_integer() {
+                        self.runtime_error("Operands must be integers.");
                         return Err(InterpretError::Runtime);
                     }
-                    let b = self.stack.pop().unwrap().as_float();
-                    let a = self.stack.pop().unwrap().as_float();
-                    self.stack.push(Value::Bool(a $op b));
-                };
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    let result = match (a, b) {
+                        (Value::Int(a), Value::Int(b)) => Value::Int(a $op b),
+                        (a, b) => self.integer(&a.as_bigint() $op &b.as_bigint())?,
+                    };
+                    self.stack.push(result);
+                }
             }
         }
 
         loop {
-            let op: OpCode = unsafe { std::mem::transmute(chunk.code[self.ip]) };
-            self.ip += 1;
+            let op: OpCode = unsafe { std::mem::transmute(self.read_byte()) };
 
             match op {
                 OpCode::Add => {
-                    if self.peek(0).is_float() || self.peek(1).is_float() {
-                        let b = self.stack.pop().unwrap().as_float();
-                        let a = self.stack.pop().unwrap().as_float();
-                        self.stack.push(Value::Float(a + b));
-                    } else if self.peek(0).is_str() || self.peek(1).is_str() {
-                        let b = self.stack.pop();
-                        let a = self.stack.pop();
-                        self.stack.push(Value::Str(
-                            a.unwrap().as_str().to_owned() + b.unwrap().as_str(),
-                        ));
+                    if self.peek(0).is_number() && self.peek(1).is_number() {
+                        binop_float!(+, checked_add);
+                    } else if self.peek(0).is_str() && self.peek(1).is_str() {
+                        let b = self.stack.pop().unwrap();
+                        let a = self.stack.pop().unwrap();
+                        let result = self.intern(a.as_str().to_owned() + b.as_str());
+                        self.stack.push(Value::Str(result));
                     } else {
-                        self.runtime_error(chunk, "Operands must be two numbers or two strings.");
+                        self.runtime_error("Operands must be two numbers or two strings.");
                         return Err(InterpretError::Runtime);
                     }
                 }
-                OpCode::Subtract => binop_float!(-),
-                OpCode::Multiply => binop_float!(*),
-                OpCode::Divide => binop_float!(/),
-                OpCode::Negate => {
-                    let previous = self.stack.pop().unwrap();
-
-                    if !previous.is_float() {
-                        self.runtime_error(chunk, &*format!("Cannot negate {}", previous));
+                OpCode::Subtract => binop_float!(-, checked_sub),
+                OpCode::Multiply => binop_float!(*, checked_mul),
+                OpCode::Divide => {
+                    if self.peek(1).is_integer() && matches!(*self.peek(0), Value::Int(0)) {
+                        self.runtime_error("Division by zero.");
+                        return Err(InterpretError::Runtime);
+                    }
+                    binop_float!(/, checked_div)
+                }
+                // Like Rust's `%`, the remainder truncates and so takes the sign of the
+                // left operand, for floats and big integers as well
+                OpCode::Modulo => {
+                    if self.peek(1).is_integer() && matches!(*self.peek(0), Value::Int(0)) {
+                        self.runtime_error("Division by zero.");
+                        return Err(InterpretError::Runtime);
+                    }
+                    binop_float!(%, checked_rem)
+                }
+                OpCode::Power => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be a number");
+                        return Err(InterpretError::Runtime);
+                    }
+                    let exponent = self.stack.pop().unwrap();
+                    let base = self.stack.pop().unwrap();
+                    let result = self.power(base, exponent)?;
+                    self.stack.push(result);
+                }
+                OpCode::BitAnd => binop_int!(&),
+                OpCode::BitOr => binop_int!(|),
+                OpCode::BitXor => binop_int!(^),
+                OpCode::BitNot => {
+                    let result = match self.stack.pop().unwrap() {
+                        Value::Int(n) => Value::Int(!n),
+                        Value::BigInt(n) => self.integer(!&*n)?,
+                        _ => {
+                            self.runtime_error("Operand must be an integer.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+                    self.stack.push(result);
+                }
+                OpCode::ShiftLeft | OpCode::ShiftRight => {
+                    if !self.peek(0).is_integer() || !self.peek(1).is_integer() {
+                        self.runtime_error("Operands must be integers.");
                         return Err(InterpretError::Runtime);
                     }
+                    let amount = self.stack.pop().unwrap();
+                    let value = self.stack.pop().unwrap();
+                    let result = self.shift(value, amount, op)?;
+                    self.stack.push(result);
+                }
+                OpCode::Negate => {
+                    let previous = self.stack.pop().unwrap();
 
-                    let number = previous.as_float();
-                    self.stack.push(Value::Float(-number));
+                    let negated = match previous {
+                        Value::Int(number) => match number.checked_neg() {
+                            Some(negated) => Value::Int(negated),
+                            None => self.integer(-&BigInt::from(number))?,
+                        },
+                        Value::BigInt(number) => self.integer(-&*number)?,
+                        Value::Float(number) => Value::Float(-number),
+                        _ => {
+                            self.runtime_error(&format!("Cannot negate {}", previous));
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+                    self.stack.push(negated);
                 }
                 OpCode::Constant => {
-                    let constant_index = chunk.code[self.ip] as usize;
-                    self.ip += 1;
-                    self.stack.push(chunk.constants[constant_index].clone());
+                    let constant = self.read_constant();
+                    self.stack.push(constant);
                 }
                 OpCode::Not => {
                     let value = self.stack.pop().unwrap().is_falsey();
@@ -126,127 +386,1034 @@ impl VM {
                 }
                 OpCode::Less => binop_bool!(<),
                 OpCode::Greater => binop_bool!(>),
+                OpCode::Print => {
+                    let value = self.stack.pop().unwrap();
+                    println!("{}", value);
+                }
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::Copy => {
+                    let distance = self.read_byte() as usize;
+                    let value = *self.peek(distance);
+                    self.stack.push(value);
+                }
+                OpCode::Bury => {
+                    let distance = self.read_byte() as usize;
+                    let value = self.stack.pop().unwrap();
+                    let slot = self.stack.len() - distance;
+                    self.stack.insert(slot, value);
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slot_base;
+                    self.stack.push(self.stack[base + slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slot_base;
+                    self.stack[base + slot] = *self.peek(0);
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = match *upvalue.borrow() {
+                        Upvalue::Open(index) => self.stack[index],
+                        Upvalue::Closed(value) => value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = *self.peek(0);
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(index) => self.stack[*index] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant() {
+                        Value::Function(function) => function,
+                        _ => unreachable!("Closure operand must be a function"),
+                    };
+
+                    let mut closure = Closure::new(function);
+                    for _ in 0..closure.function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        let upvalue = if is_local {
+                            let slot = self.frame().slot_base + index;
+                            self.capture_upvalue(slot)
+                        } else {
+                            self.frame().closure.upvalues[index]
+                        };
+
+                        closure.upvalues.push(upvalue);
+                    }
+
+                    let closure = self.alloc(closure);
+                    self.stack.push(Value::Closure(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::GetProperty => {
+                    let instance = match self.peek(0) {
+                        Value::Instance(instance) => *instance,
+                        Value::Module(module) => {
+                            let module = *module;
+                            let name = self.read_string();
+                            let value = self.module_global(module, name)?;
+                            self.stack.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        _ => {
+                            self.runtime_error("Only instances have properties.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+
+                    let name = self.read_string();
+                    let value = instance.fields.borrow().get(&name).copied();
+
+                    if let Some(value) = value {
+                        self.stack.pop();
+                        self.stack.push(value);
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                OpCode::SetProperty => {
+                    let instance = match self.peek(1) {
+                        Value::Instance(instance) => *instance,
+                        _ => {
+                            self.runtime_error("Only instances have fields.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+
+                    let name = self.read_string();
+                    let value = self.stack.pop().unwrap();
+                    instance.fields.borrow_mut().insert(name, value);
+
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = match self.stack.pop().unwrap() {
+                        Value::Class(class) => class,
+                        _ => unreachable!("Superclass must be a class"),
+                    };
+
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Call => {
+                    let argument_count = self.read_byte() as usize;
+                    let callee = *self.peek(argument_count);
+                    self.call_value(callee, argument_count)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let argument_count = self.read_byte() as usize;
+                    self.invoke(name, argument_count)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let argument_count = self.read_byte() as usize;
+                    let superclass = match self.stack.pop().unwrap() {
+                        Value::Class(class) => class,
+                        _ => unreachable!("Superclass must be a class"),
+                    };
+
+                    self.invoke_from_class(superclass, name, argument_count)?;
+                }
+                OpCode::Class => {
+                    let name = self.read_string().to_string();
+                    let class = self.alloc(Class::new(name));
+                    self.stack.push(Value::Class(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Class(class) => *class,
+                        _ => {
+                            self.runtime_error("Superclass must be a class.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+
+                    if let Value::Class(subclass) = self.peek(0) {
+                        let methods = superclass.methods.borrow();
+                        subclass
+                            .methods
+                            .borrow_mut()
+                            .extend(methods.iter().map(|(name, method)| (*name, *method)));
+                    }
+
+                    self.stack.pop();
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let method = match self.stack.pop().unwrap() {
+                        Value::Closure(closure) => closure,
+                        _ => unreachable!("Method must be a closure"),
+                    };
+
+                    if let Value::Class(class) = self.peek(0) {
+                        class.methods.borrow_mut().insert(name, method);
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::BuildList => {
+                    let element_count = self.read_byte() as usize;
+                    let elements_start = self.stack.len() - element_count;
+                    // Elements stay on the stack, and so rooted, until the list owns them
+                    let elements = self.stack[elements_start..].to_vec();
+                    let list = self.alloc(List::new(elements));
+                    self.stack.truncate(elements_start);
+                    self.stack.push(Value::List(list));
+                }
+                OpCode::IndexGet => {
+                    let index = self.stack.pop().unwrap();
+                    let value = match self.stack.pop().unwrap() {
+                        Value::List(list) => {
+                            let elements = list.elements.borrow();
+                            elements[self.list_index(index, elements.len())?]
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(index)?;
+                            let value = map.entries.borrow().get(&key).copied();
+                            match value {
+                                Some(value) => value,
+                                None => {
+                                    self.runtime_error(&format!("Undefined key '{}'.", key));
+                                    return Err(InterpretError::Runtime);
+                                }
+                            }
+                        }
+                        _ => {
+                            self.runtime_error("Only lists and maps can be indexed.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::IndexSet => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.pop().unwrap();
+                    match self.stack.pop().unwrap() {
+                        Value::List(list) => {
+                            let mut elements = list.elements.borrow_mut();
+                            let index = self.list_index(index, elements.len())?;
+                            elements[index] = value;
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(index)?;
+                            map.entries.borrow_mut().insert(key, value);
+                        }
+                        _ => {
+                            self.runtime_error("Only lists and maps can be indexed.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    }
+                    self.stack.push(value);
+                }
+                OpCode::BuildMap => {
+                    let entry_count = self.read_byte() as usize;
+                    let entries_start = self.stack.len() - entry_count * 2;
+
+                    let mut entries = HashMap::with_capacity(entry_count);
+                    for i in (entries_start..self.stack.len()).step_by(2) {
+                        let key = self.map_key(self.stack[i])?;
+                        entries.insert(key, self.stack[i + 1]);
+                    }
+
+                    let map = self.alloc(Map::new(entries));
+                    self.stack.truncate(entries_start);
+                    self.stack.push(Value::Map(map));
+                }
+                OpCode::Contains => {
+                    let collection = self.stack.pop().unwrap();
+                    let value = self.stack.pop().unwrap();
+                    let contains = match collection {
+                        Value::Map(map) => {
+                            value.is_hashable() && map.entries.borrow().contains_key(&value)
+                        }
+                        Value::List(list) => list.elements.borrow().contains(&value),
+                        _ => {
+                            self.runtime_error("Right operand of 'in' must be a list or map.");
+                            return Err(InterpretError::Runtime);
+                        }
+                    };
+                    self.stack.push(Value::Bool(contains));
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_short() as usize;
+                    let catch_ip = self.frame().ip + offset;
+                    let stack_len = self.stack.len();
+                    self.frame_mut().handlers.push(Handler {
+                        catch_ip,
+                        stack_len,
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.frame_mut().handlers.pop();
+                }
+                OpCode::Throw => {
+                    let value = self.stack.pop().unwrap();
+                    self.exception = Some(Exception::Thrown(value));
+                    return Err(InterpretError::Runtime);
+                }
+                OpCode::Import => {
+                    let path = self.read_string();
+                    self.import(path)?;
+                }
+                OpCode::ImportFrom => {
+                    let name = self.read_string();
+                    let module = match self.peek(0) {
+                        Value::Module(module) => *module,
+                        _ => unreachable!("Imported names must come from a module"),
+                    };
+                    let value = self.module_global(module, name)?;
+                    self.stack.push(value);
+                }
+                OpCode::Stringify => {
+                    if !self.peek(0).is_str() {
+                        let string = self.peek(0).to_string();
+                        let string = self.intern(string);
+                        self.stack.pop();
+                        self.stack.push(Value::Str(string));
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.stack.pop().unwrap();
+                    let module = self.frame().closure.function.module;
+                    module.globals.borrow_mut().insert(name, value);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    let module = self.frame().closure.function.module;
+                    let value = module.globals.borrow().get(&name).copied();
+                    if let Some(value) = value.or_else(|| self.builtins.get(&name).copied()) {
+                        self.stack.push(value);
+                    } else {
+                        let message = format!("Undefined variable '{}'.", name);
+                        self.runtime_error(&message);
+                        return Err(InterpretError::Runtime);
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let module = self.frame().closure.function.module;
+                    let value = *self.peek(0);
+                    let mut globals = module.globals.borrow_mut();
+                    let defined = globals.get_mut(&name).map(|global| *global = value);
+                    drop(globals);
+
+                    if defined.is_none() {
+                        let message = format!("Undefined variable '{}'.", name);
+                        self.runtime_error(&message);
+                        return Err(InterpretError::Runtime);
+                    }
+                }
                 OpCode::Return => {
-                    let result = self.stack.pop().as_ref().unwrap().clone();
-                    return Ok(result);
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+
+                    self.close_upvalues(frame.slot_base);
+                    self.stack.truncate(frame.slot_base);
+
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+
+                    // A module's top-level code hands the module back to the import that ran it
+                    let function = frame.closure.function;
+                    if function.name.is_none() {
+                        function.module.loaded.set(true);
+                        self.stack.push(Value::Module(function.module));
+                    } else {
+                        self.stack.push(result);
+                    }
                 }
             }
         }
     }
 
-    fn runtime_error(&self, chunk: &Chunk, message: &str) {
-        let ip = self.ip;
-        let line = chunk.lines[ip];
-        eprintln!("[line {0}] {1}", line, message);
-    }
-
-    fn peek(&self, offset: usize) -> &Value {
-        let size = self.stack.len();
-        &self.stack[size - offset - 1]
-    }
-}
+    fn call_value(&mut self, callee: Value, argument_count: usize) -> Result<(), InterpretError> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, argument_count),
+            Value::Native(native) => {
+                if argument_count != native.arity {
+                    let message = format!(
+                        "Expected {} arguments but got {}.",
+                        native.arity, argument_count
+                    );
+                    self.runtime_error(&message);
+                    return Err(InterpretError::Runtime);
+                }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::bytecode::{Chunk, OpCode};
+                let arguments_start = self.stack.len() - argument_count;
+                let arguments = self.stack[arguments_start..].to_vec();
 
-    #[test]
-    fn division() -> Result<(), InterpretError> {
-        let chunk = Chunk::new(
-            vec![
-                OpCode::Constant as u8,
-                0,
-                OpCode::Constant as u8,
-                1,
-                OpCode::Divide as u8,
-                OpCode::Return as u8,
-            ],
-            vec![Value::Float(100.0), Value::Float(5.0)],
-            vec![123, 123, 123, 123, 123, 123, 123],
-        );
-        let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+                match (native.function)(self, &arguments) {
+                    Ok(result) => {
+                        self.stack.truncate(arguments_start - 1);
+                        self.stack.push(result);
+                        Ok(())
+                    }
+                    Err(message) => {
+                        self.runtime_error(&message);
+                        Err(InterpretError::Runtime)
+                    }
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - argument_count - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(bound.method, argument_count)
+            }
+            Value::Class(class) => {
+                let initializer = class.methods.borrow().get(&self.init_string).copied();
 
-        assert_eq!(result, Value::Float(20.0));
+                let instance = Instance::new(class);
+                let slot = self.stack.len() - argument_count - 1;
+                self.stack[slot] = Value::Instance(self.alloc(instance));
 
-        Ok(())
+                if let Some(initializer) = initializer {
+                    self.call(initializer, argument_count)
+                } else if argument_count != 0 {
+                    let message = format!("Expected 0 arguments but got {}.", argument_count);
+                    self.runtime_error(&message);
+                    Err(InterpretError::Runtime)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => {
+                self.runtime_error("Can only call functions and classes.");
+                Err(InterpretError::Runtime)
+            }
+        }
     }
 
-    #[test]
-    fn negation() -> Result<(), InterpretError> {
-        let chunk = Chunk::new(
-            vec![
-                OpCode::Constant as u8,
-                0,
-                OpCode::Negate as u8,
-                OpCode::Return as u8,
-            ],
-            vec![Value::Float(100.0)],
-            vec![123, 123, 123, 123],
-        );
-        let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+    fn invoke(&mut self, name: Gc<String>, argument_count: usize) -> Result<(), InterpretError> {
+        let instance = match self.peek(argument_count) {
+            Value::Instance(instance) => *instance,
+            Value::Module(module) => {
+                let function = self.module_global(*module, name)?;
+                let slot = self.stack.len() - argument_count - 1;
+                self.stack[slot] = function;
+                return self.call_value(function, argument_count);
+            }
+            _ => {
+                self.runtime_error("Only instances have methods.");
+                return Err(InterpretError::Runtime);
+            }
+        };
 
-        assert_eq!(result, Value::Float(-100.0));
+        let field = instance.fields.borrow().get(&name).copied();
+        if let Some(field) = field {
+            let slot = self.stack.len() - argument_count - 1;
+            self.stack[slot] = field;
+            return self.call_value(field, argument_count);
+        }
 
-        Ok(())
+        self.invoke_from_class(instance.class, name, argument_count)
     }
 
-    #[test]
-    fn invalid_negation() {
-        let chunk = Chunk::new(
-            vec![
-                OpCode::Constant as u8,
-                0,
-                OpCode::Negate as u8,
-                OpCode::Return as u8,
-            ],
-            vec![Value::Bool(true)],
-            vec![123, 123, 123, 123],
-        );
-        let mut vm = VM::new();
-        let result = vm.run(&chunk);
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<Class>,
+        name: Gc<String>,
+        argument_count: usize,
+    ) -> Result<(), InterpretError> {
+        let method = class.methods.borrow().get(&name).copied();
 
-        assert_eq!(result, Err(InterpretError::Runtime));
+        match method {
+            Some(method) => self.call(method, argument_count),
+            None => {
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                Err(InterpretError::Runtime)
+            }
+        }
     }
 
-    #[test]
-    fn not() -> Result<(), InterpretError> {
-        let chunk = Chunk::new(
-            vec![OpCode::True as u8, OpCode::Not as u8, OpCode::Return as u8],
-            vec![],
-            vec![123, 123, 123],
-        );
-        let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+    fn bind_method(&mut self, class: Gc<Class>, name: Gc<String>) -> Result<(), InterpretError> {
+        let method = class.methods.borrow().get(&name).copied();
 
-        assert_eq!(result, Value::Bool(false));
+        match method {
+            Some(method) => {
+                let receiver = *self.peek(0);
+                let bound = self.alloc(BoundMethod::new(receiver, method));
+                self.stack.pop();
+                self.stack.push(Value::BoundMethod(bound));
+                Ok(())
+            }
+            None => {
+                self.runtime_error(&format!("Undefined property '{}'.", name));
+                Err(InterpretError::Runtime)
+            }
+        }
+    }
 
-        Ok(())
+    fn map_key(&mut self, key: Value) -> Result<Value, InterpretError> {
+        if key.is_hashable() {
+            Ok(key)
+        } else {
+            self.runtime_error("Map keys must be strings, numbers, booleans or nil.");
+            Err(InterpretError::Runtime)
+        }
     }
 
-    #[test]
-    fn less() -> Result<(), InterpretError> {
-        let chunk = Chunk::new(
-            vec![
-                OpCode::Constant as u8,
-                0,
-                OpCode::Constant as u8,
-                1,
-                OpCode::Less as u8,
-                OpCode::Return as u8,
-            ],
-            vec![Value::Float(-1.0), Value::Float(1.0)],
-            vec![123, 123, 123, 123, 123, 123],
-        );
-        let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+    fn list_index(&mut self, index: Value, length: usize) -> Result<usize, InterpretError> {
+        let position = match index {
+            Value::Int(position) => position,
+            // Too large to be in bounds either way
+            Value::BigInt(position) if position.is_negative() => -1,
+            Value::BigInt(_) => i64::MAX,
+            _ => {
+                self.runtime_error("List index must be an integer.");
+                return Err(InterpretError::Runtime);
+            }
+        };
 
-        assert_eq!(result, Value::Bool(true));
+        if position < 0 {
+            self.runtime_error(&format!("List index {} is negative.", index));
+            return Err(InterpretError::Runtime);
+        }
 
-        Ok(())
-    }
+        if position as u64 >= length as u64 {
+            let message = format!(
+                "List index {} is out of bounds for length {}.",
+                index, length
+            );
+            self.runtime_error(&message);
+            return Err(InterpretError::Runtime);
+        }
+
+        Ok(position as usize)
+    }
+
+    // Integers raised to non-negative integer powers stay exact; anything else is a
+    // float, so `2 ** -1` is 0.5
+    fn power(&mut self, base: Value, exponent: Value) -> Result<Value, InterpretError> {
+        if !base.is_integer() || !exponent.is_integer() || exponent.as_bigint().is_negative() {
+            return Ok(Value::Float(base.as_float().powf(exponent.as_float())));
+        }
+
+        // These never grow, so any exponent will do
+        match base {
+            Value::Int(0) if matches!(exponent, Value::Int(0)) => return Ok(Value::Int(1)),
+            Value::Int(0 | 1) => return Ok(base),
+            Value::Int(-1) => {
+                let odd = (&exponent.as_bigint() & &BigInt::from(1)).to_i64() == Some(1);
+                return Ok(Value::Int(if odd { -1 } else { 1 }));
+            }
+            _ => (),
+        }
+
+        // The result has at least (bits - 1) * exponent + 1 bits, so this rules out
+        // the hopeless cases before any work is done
+        let bits = base.as_bigint().bit_length();
+        let exponent = match exponent {
+            Value::Int(exponent)
+                if exponent <= u32::MAX as i64
+                    && (bits - 1) * exponent as u64 <= INTEGER_BITS_MAX =>
+            {
+                exponent as u32
+            }
+            _ => {
+                self.runtime_error("Exponent is too large.");
+                return Err(InterpretError::Runtime);
+            }
+        };
+
+        match base {
+            Value::Int(base) => match base.checked_pow(exponent) {
+                Some(result) => Ok(Value::Int(result)),
+                None => self.integer(BigInt::from(base).pow(exponent)),
+            },
+            _ => self.integer(base.as_bigint().pow(exponent)),
+        }
+    }
+
+    fn shift(&mut self, value: Value, amount: Value, op: OpCode) -> Result<Value, InterpretError> {
+        if amount.as_bigint().is_negative() {
+            self.runtime_error("Shift amount must not be negative.");
+            return Err(InterpretError::Runtime);
+        }
+
+        let bits = value.as_bigint().bit_length();
+        let amount = match amount {
+            Value::Int(amount) => amount as u64,
+            _ => u64::MAX,
+        };
+
+        if bits == 0 {
+            return Ok(Value::Int(0));
+        }
+
+        if matches!(op, OpCode::ShiftRight) {
+            // Every bit is shifted out, leaving only the sign
+            if amount >= bits {
+                return Ok(Value::Int(if value.as_bigint().is_negative() {
+                    -1
+                } else {
+                    0
+                }));
+            }
+
+            return match value {
+                Value::Int(n) => Ok(Value::Int(n >> amount)),
+                _ => self.integer(&value.as_bigint() >> amount as usize),
+            };
+        }
+
+        if bits + amount > INTEGER_BITS_MAX {
+            self.runtime_error("Shift amount is too large.");
+            return Err(InterpretError::Runtime);
+        }
+
+        let amount = amount as u32;
+        match value {
+            Value::Int(n) => match n.checked_shl(amount) {
+                // Shifting back recovers `n` only if no bits were lost
+                Some(shifted) if shifted >> amount == n => Ok(Value::Int(shifted)),
+                _ => self.integer(&BigInt::from(n) << amount as usize),
+            },
+            _ => self.integer(&value.as_bigint() << amount as usize),
+        }
+    }
+
+    /// Wraps an integer result as an `Int` when it fits, or allocates it otherwise.
+    /// Fails if it has more than `INTEGER_BITS_MAX` bits.
+    pub fn integer(&mut self, n: BigInt) -> Result<Value, InterpretError> {
+        if n.bit_length() > INTEGER_BITS_MAX {
+            self.runtime_error("Integer is too large.");
+            return Err(InterpretError::Runtime);
+        }
+
+        Ok(match n.to_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::BigInt(self.alloc(n)),
+        })
+    }
+
+    /// Loads the module at `path`, relative to the importing module, and runs its
+    /// top-level code unless it has been imported before. The module ends up on the stack.
+    fn import(&mut self, path: Gc<String>) -> Result<(), InterpretError> {
+        let importer = self.frame().closure.function.module;
+        let directory = importer.path.parent().unwrap_or(Path::new(""));
+
+        let canonical = match fs::canonicalize(directory.join(path.as_str())) {
+            Ok(canonical) => canonical,
+            Err(error) => return self.import_error(path, &error.to_string()),
+        };
+
+        if let Some(module) = self.modules.get(&canonical).copied() {
+            if !module.loaded.get() {
+                return self.import_error(path, "it is already being imported, forming a cycle");
+            }
+
+            self.stack.push(Value::Module(module));
+            return Ok(());
+        }
+
+        let source = match fs::read_to_string(&canonical) {
+            Ok(source) => source,
+            Err(error) => return self.import_error(path, &error.to_string()),
+        };
+
+        let module = self.alloc(Module::new(canonical.clone()));
+        self.modules.insert(canonical.clone(), module);
+
+        let mut compiler = Compiler::new(&source, &mut self.heap, module);
+        compiler.name_module_in_errors();
+        let function = match compiler.compile() {
+            Ok(function) => function,
+            Err(_) => {
+                self.modules.remove(&canonical);
+                return self.import_error(path, "it failed to compile");
+            }
+        };
+
+        self.stack.push(Value::Function(function));
+        let closure = self.alloc(Closure::new(function));
+        self.stack.pop();
+
+        self.stack.push(Value::Closure(closure));
+        self.call(closure, 0)
+    }
+
+    fn import_error(&mut self, path: Gc<String>, reason: &str) -> Result<(), InterpretError> {
+        let frame = self.frame();
+        let function = &frame.closure.function;
+        let message = format!(
+            "Can't import '{}' from {}, line {}: {}.",
+            path,
+            function.module,
+            function.chunk.lines[frame.ip - 1],
+            reason
+        );
+        self.runtime_error(&message);
+        Err(InterpretError::Runtime)
+    }
+
+    fn module_global(
+        &mut self,
+        module: Gc<Module>,
+        name: Gc<String>,
+    ) -> Result<Value, InterpretError> {
+        let value = module.globals.borrow().get(&name).copied();
+        match value {
+            Some(value) => Ok(value),
+            None => {
+                let message = format!("Module {} has no variable '{}'.", module, name);
+                self.runtime_error(&message);
+                Err(InterpretError::Runtime)
+            }
+        }
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, argument_count: usize) -> Result<(), InterpretError> {
+        let function = &closure.function;
+        if argument_count != function.arity {
+            let message = format!(
+                "Expected {} arguments but got {}.",
+                function.arity, argument_count
+            );
+            self.runtime_error(&message);
+            return Err(InterpretError::Runtime);
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            self.runtime_error("Stack overflow.");
+            return Err(InterpretError::Runtime);
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - argument_count - 1,
+            handlers: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<RefCell<Upvalue>> {
+        let position =
+            self.open_upvalues
+                .binary_search_by_key(&slot, |upvalue| match *upvalue.borrow() {
+                    Upvalue::Open(index) => index,
+                    Upvalue::Closed(_) => unreachable!("Closed upvalue in the open list"),
+                });
+
+        match position {
+            Ok(existing) => self.open_upvalues[existing],
+            Err(insert_at) => {
+                let upvalue = self.alloc(RefCell::new(Upvalue::Open(slot)));
+                self.open_upvalues.insert(insert_at, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(index) => index,
+                Upvalue::Closed(_) => unreachable!("Closed upvalue in the open list"),
+            };
+
+            if slot < last_slot {
+                break;
+            }
+
+            let upvalue = self.open_upvalues.pop().unwrap();
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot]);
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let constant_index = self.read_byte() as usize;
+        self.frame().closure.function.chunk.constants[constant_index]
+    }
+
+    fn read_string(&mut self) -> Gc<String> {
+        match self.read_constant() {
+            Value::Str(string) => string,
+            _ => unreachable!("Operand must be a string constant"),
+        }
+    }
+
+    /// Raises a built-in error at the current instruction. Callers then return
+    /// `InterpretError::Runtime` so that `run` can look for a handler.
+    fn runtime_error(&mut self, message: &str) {
+        let frame = self.frame();
+        let line = frame.closure.function.chunk.lines[frame.ip - 1];
+        self.exception = Some(Exception::Error {
+            message: message.to_string(),
+            line,
+        });
+    }
+
+    /// Transfers control to the innermost handler for the pending exception, discarding
+    /// the frames above it. Returns false, leaving everything in place, if there is none.
+    fn unwind(&mut self) -> bool {
+        let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| !frame.handlers.is_empty())
+        else {
+            return false;
+        };
+
+        let exception = match self.exception.take().unwrap() {
+            Exception::Thrown(value) => value,
+            Exception::Error { message, line } => self.error_value(message, line),
+        };
+
+        self.discard_frames(depth + 1);
+        let handler = self.frame_mut().handlers.pop().unwrap();
+
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(exception);
+        self.frame_mut().ip = handler.catch_ip;
+
+        true
+    }
+
+    fn error_value(&mut self, message: String, line: u32) -> Value {
+        let instance = self.alloc(Instance::new(self.error_class));
+        // Rooted while the message is allocated
+        self.stack.push(Value::Instance(instance));
+        let message = self.intern(message);
+        self.stack.pop();
+
+        let mut fields = instance.fields.borrow_mut();
+        fields.insert(self.message_string, Value::Str(message));
+        fields.insert(self.line_string, Value::Int(line as i64));
+        Value::Instance(instance)
+    }
+
+    // Prints an uncaught exception with a stack trace and resets the VM
+    fn report_exception(&mut self) {
+        match self.exception.take() {
+            Some(Exception::Error { message, .. }) => eprintln!("{}", message),
+            Some(Exception::Thrown(value)) => match self.thrown_message(value) {
+                Some(message) => eprintln!("{}", message),
+                None => eprintln!("{}", value),
+            },
+            None => (),
+        }
+
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let function = &frame.closure.function;
+            let line = function.chunk.lines[frame.ip - 1];
+            match &function.name {
+                Some(name) => eprintln!("[line {}] in {}()", line, name),
+                // Imported modules are named by their path
+                None if depth > 0 => eprintln!("[line {}] in {}", line, function.module),
+                None => eprintln!("[line {}] in script", line),
+            }
+        }
+
+        self.discard_frames(0);
+        self.stack.clear();
+        self.open_upvalues.clear();
+    }
+
+    // The message of a thrown error, so it reads the same as one raised by the VM
+    fn thrown_message(&self, value: Value) -> Option<Value> {
+        match value {
+            Value::Instance(instance) => {
+                instance.fields.borrow().get(&self.message_string).copied()
+            }
+            _ => None,
+        }
+    }
+
+    /// Discards the frames above the first `len`. A module whose top-level code is cut
+    /// short this way is dropped from the cache, so importing it again runs it again
+    /// rather than reporting a cycle.
+    fn discard_frames(&mut self, len: usize) {
+        // The first frame runs the main module, which stays cached
+        for frame in self.frames.iter().skip(len.max(1)) {
+            let function = &frame.closure.function;
+            if function.name.is_none() {
+                self.modules.remove(&function.module.path);
+            }
+        }
+
+        self.frames.truncate(len);
+    }
+
+    fn peek(&self, offset: usize) -> &Value {
+        let size = self.stack.len();
+        &self.stack[size - offset - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{Chunk, OpCode};
+
+    fn script(vm: &mut VM, chunk: Chunk) -> Gc<Function> {
+        let mut function = Function::new(None, vm.main);
+        function.chunk = chunk;
+        vm.heap.alloc(function)
+    }
+
+    fn global(vm: &mut VM, name: &str) -> Value {
+        let name = vm.heap.intern(name);
+        vm.main.globals.borrow()[&name]
+    }
+
+    #[test]
+    fn division() -> Result<(), InterpretError> {
+        let chunk = Chunk::new(
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Divide as u8,
+                OpCode::Return as u8,
+            ],
+            vec![Value::Float(100.0), Value::Float(5.0)],
+            vec![123, 123, 123, 123, 123, 123, 123],
+        );
+        let mut vm = VM::new();
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
+
+        assert!(matches!(result, Value::Float(n) if n == 20.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn negation() -> Result<(), InterpretError> {
+        let chunk = Chunk::new(
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Negate as u8,
+                OpCode::Return as u8,
+            ],
+            vec![Value::Float(100.0)],
+            vec![123, 123, 123, 123],
+        );
+        let mut vm = VM::new();
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
+
+        assert!(matches!(result, Value::Float(n) if n == -100.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_negation() {
+        let chunk = Chunk::new(
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Negate as u8,
+                OpCode::Return as u8,
+            ],
+            vec![Value::Bool(true)],
+            vec![123, 123, 123, 123],
+        );
+        let mut vm = VM::new();
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script);
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn not() -> Result<(), InterpretError> {
+        let chunk = Chunk::new(
+            vec![OpCode::True as u8, OpCode::Not as u8, OpCode::Return as u8],
+            vec![],
+            vec![123, 123, 123],
+        );
+        let mut vm = VM::new();
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
+
+        assert_eq!(result, Value::Bool(false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn less() -> Result<(), InterpretError> {
+        let chunk = Chunk::new(
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Less as u8,
+                OpCode::Return as u8,
+            ],
+            vec![Value::Float(-1.0), Value::Float(1.0)],
+            vec![123, 123, 123, 123, 123, 123],
+        );
+        let mut vm = VM::new();
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
+
+        assert_eq!(result, Value::Bool(true));
+
+        Ok(())
+    }
 
     #[test]
     fn greater() -> Result<(), InterpretError> {
@@ -263,7 +1430,8 @@ mod tests {
             vec![123, 123, 123, 123, 123, 123],
         );
         let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
 
         assert_eq!(result, Value::Bool(false));
 
@@ -285,7 +1453,8 @@ mod tests {
             vec![123, 123, 123, 123, 123, 123],
         );
         let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
 
         assert_eq!(result, Value::Bool(true));
 
@@ -294,6 +1463,9 @@ mod tests {
 
     #[test]
     fn concatenation() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        let hello = vm.heap.intern("hello, ");
+        let world = vm.heap.intern("world!");
         let chunk = Chunk::new(
             vec![
                 OpCode::Constant as u8,
@@ -303,16 +1475,1017 @@ mod tests {
                 OpCode::Add as u8,
                 OpCode::Return as u8,
             ],
-            vec![
-                Value::Str(String::from("hello, ")),
-                Value::Str(String::from("world!")),
-            ],
+            vec![Value::Str(hello), Value::Str(world)],
             vec![123, 123, 123, 123, 123, 123],
         );
+        let script = script(&mut vm, chunk);
+        let result = vm.interpret(script)?;
+
+        assert_eq!(result.as_str(), "hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn concatenation_result_is_interned() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source("var a = \"ab\"; var b = \"a\" + \"b\"; var same = a == b;")?;
+
+        let a = global(&mut vm, "a");
+        let b = global(&mut vm, "b");
+        assert!(matches!((a, b), (Value::Str(a), Value::Str(b)) if Gc::ptr_eq(a, b)));
+        assert_eq!(global(&mut vm, "same"), Value::Bool(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn statements() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("print 1 + 2;\n\"a\" + \"b\";")?;
+
+        assert_eq!(result, Value::Nil);
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn globals() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source("var a = 1; var b; b = a + 2;")?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "b"), Value::Int(3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn undefined_global() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("print missing;");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn locals() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source("var r; { var a = 1; { var a = 2; r = a; } r = r + a; }")?;
+
+        assert!(matches!(global(&mut vm, "r"), Value::Int(3)));
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn control_flow() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var a; var b; var c = 0; var d = 0;
+            if (1 < 2) a = 1; else a = 2;
+            if (nil) b = 1; else b = 2;
+            while (c < 5) c = c + 1;
+            for (var i = 0; i < 3; i = i + 1) d = d + i;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "b"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "c"), Value::Int(5)));
+        assert!(matches!(global(&mut vm, "d"), Value::Int(3)));
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_circuit() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var calls = 0;
+            var a = nil and (calls = 1);
+            var b = 2 or (calls = 2);
+            var c = \"left\" and \"right\";
+            var d = false or nil;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "calls"), Value::Int(0)));
+        assert_eq!(global(&mut vm, "a"), Value::Nil);
+        assert!(matches!(global(&mut vm, "b"), Value::Int(2)));
+        assert_eq!(global(&mut vm, "c").as_str(), "right");
+        assert_eq!(global(&mut vm, "d"), Value::Nil);
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_calls() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            fun nothing() {}
+            var a = fib(10);
+            var b = nothing();",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(55)));
+        assert_eq!(global(&mut vm, "b"), Value::Nil);
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn arity_mismatch() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("fun f(a) {} f(1, 2);");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn call_non_callable() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("var a = 1; a();");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn closures() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "fun counter() { var count = 0; fun increment() { count = count + 1; return count; } return increment; }
+            var next = counter();
+            next();
+            var a = next();
+            var get; var set;
+            { var shared = 1; fun g() { return shared; } fun s(v) { shared = v; } get = g; set = s; }
+            set(10);
+            var b = get();",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "b"), Value::Int(10)));
+        assert!(vm.open_upvalues.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn instance_fields() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "class Point {}
+            var p = Point();
+            p.x = 1;
+            p.y = p.x + 2;
+            var y = p.y;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "y"), Value::Int(3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn undefined_property() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("class Point {} Point().x;");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn methods() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "class Counter {
+                init(start) { this.count = start; }
+                increment() { this.count = this.count + 1; return this; }
+            }
+            var counter = Counter(10);
+            counter.increment().increment();
+            var bound = counter.increment;
+            bound();
+            var a = counter.count;
+            var b = counter.init(1) == counter;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(13)));
+        assert_eq!(global(&mut vm, "b"), Value::Bool(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn initializer_arity() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("class A { init(a, b) {} } A(1);");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    #[test]
+    fn inheritance() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "class A {
+                init(x) { this.x = x; }
+                describe() { return \"A\"; }
+                value() { return this.x; }
+            }
+            class B < A {
+                init(x) { super.init(x * 2); }
+                describe() { var parent = super.describe; return parent() + \"B\"; }
+            }
+            var b = B(2);
+            var a = b.describe();
+            var x = b.value();",
+        )?;
+
+        assert_eq!(global(&mut vm, "a").as_str(), "AB");
+        assert!(matches!(global(&mut vm, "x"), Value::Int(4)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn inherit_non_class() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("var A = 1; class B < A {}");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+    }
+
+    fn add(_vm: &mut VM, arguments: &[Value]) -> Result<Value, String> {
+        if arguments[0].is_number() && arguments[1].is_number() {
+            Ok(Value::Float(
+                arguments[0].as_float() + arguments[1].as_float(),
+            ))
+        } else {
+            Err(String::from("add expects two numbers."))
+        }
+    }
+
+    #[test]
+    fn native_functions() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+        vm.interpret_source("var sum = add(1, 2); var time = clock();")?;
+
+        assert!(matches!(global(&mut vm, "sum"), Value::Float(n) if n == 3.0));
+        assert!(matches!(global(&mut vm, "time"), Value::Float(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn native_function_error() {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+
+        assert_eq!(
+            vm.interpret_source("add(1, nil);"),
+            Err(InterpretError::Runtime)
+        );
+        assert_eq!(vm.interpret_source("add(1);"), Err(InterpretError::Runtime));
+    }
+
+    fn collect(vm: &mut VM, _arguments: &[Value]) -> Result<Value, String> {
+        vm.collect_garbage(Marker::new());
+        Ok(Value::Nil)
+    }
+
+    #[test]
+    fn garbage_collection_keeps_roots() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.define_native("collect", 0, collect);
+        vm.interpret_source(
+            "class Box { init(value) { this.value = value; } }
+            fun make() { var local = Box(\"captured\"); fun get() { return local.value; } return get; }
+            var get = make();
+            for (var i = 0; i < 100; i = i + 1) { Box(i); }
+            collect();
+            var a = get();",
+        )?;
+
+        assert_eq!(global(&mut vm, "a").as_str(), "captured");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var xs = [1, \"two\", [3]];
+            xs[0] = xs[0] + 10;
+            var first = xs[0];
+            var nested = xs[2][0];
+            var text = \"\" + xs[1];",
+        )?;
+
+        assert!(matches!(global(&mut vm, "first"), Value::Int(11)));
+        assert!(matches!(global(&mut vm, "nested"), Value::Int(3)));
+        assert_eq!(global(&mut vm, "text").as_str(), "two");
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_index_errors() {
+        let mut vm = VM::new();
+
+        for source in [
+            "[1][-1];",
+            "[1][1];",
+            "[1][100000000000000000000];",
+            "[1][0.5];",
+            "[][0] = 1;",
+            "nil[0];",
+        ] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+    }
+
+    #[test]
+    fn list_containing_itself() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source("var xs = [1, 2]; xs[1] = xs; var ys = [xs, xs];")?;
+
+        assert_eq!(global(&mut vm, "xs").to_string(), "[1, [...]]");
+        assert_eq!(
+            global(&mut vm, "ys").to_string(),
+            "[[1, [...]], [1, [...]]]"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn maps() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var m = {\"a\": 1, 2: \"two\", true: nil};
+            m[\"a\"] = m[\"a\"] + 1;
+            m[\"new\"] = 3;
+            var a = m[\"a\"];
+            var two = m[1 + 1];
+            var hasNew = \"n\" + \"ew\" in m;
+            var hasMissing = \"missing\" in m;
+            var hasNilValue = true in m;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(2)));
+        assert_eq!(global(&mut vm, "two").as_str(), "two");
+        assert_eq!(global(&mut vm, "hasNew"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "hasMissing"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "hasNilValue"), Value::Bool(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut vm = VM::new();
+
+        for source in [
+            "print {}[\"missing\"];",
+            "print {}[[]] = 1;",
+            "print {[]: 1};",
+            "print 1 in 2;",
+        ] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+    }
+
+    #[test]
+    fn map_containing_itself() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source("var m = {}; m[\"self\"] = m; var xs = [m];")?;
+
+        assert_eq!(global(&mut vm, "m").to_string(), "{self: {...}}");
+        assert_eq!(global(&mut vm, "xs").to_string(), "[{self: {...}}]");
+
+        Ok(())
+    }
+
+    #[test]
+    fn string_interpolation() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var name = \"Ann\";
+            var s = \"Hi ${name}, ${\"next ${1 + 1}\"}! ${nil} ${ {\"k\": true}[\"k\"] }\";",
+        )?;
+
+        assert_eq!(global(&mut vm, "s").as_str(), "Hi Ann, next 2! nil true");
+
+        Ok(())
+    }
+
+    #[test]
+    fn break_and_continue() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var captured = i;
+                if (i == 1) continue;
+                if (i == 4) break;
+                fun get() { return captured; }
+                sum = sum + get();
+            }
+            var n = 0;
+            while (true) { var local = n; n = n + 1; if (local == 3) break; }",
+        )?;
+
+        assert!(matches!(global(&mut vm, "sum"), Value::Int(5)));
+        assert!(matches!(global(&mut vm, "n"), Value::Int(4)));
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_errors_are_catchable() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var message;
+            var line;
+            fun fail() {
+                return 1 + nil;
+            }
+            try {
+                fail();
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }",
+        )?;
+
+        assert_eq!(
+            global(&mut vm, "message").as_str(),
+            "Operands must be two numbers or two strings."
+        );
+        assert!(matches!(global(&mut vm, "line"), Value::Int(4)));
+        assert!(vm.stack.is_empty());
+        assert!(vm.open_upvalues.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn finally_runs_and_rethrows() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var log = \"\";
+            var get;
+            try {
+                try {
+                    throw \"first\";
+                } catch (e) {
+                    fun captured() { return e; }
+                    get = captured;
+                    throw \"second\";
+                } finally {
+                    log = log + \"finally \";
+                }
+            } catch (e) {
+                log = log + e + \" \" + get();
+            }",
+        )?;
+
+        assert_eq!(global(&mut vm, "log").as_str(), "finally second first");
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn uncaught_exception() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("try { throw 1; } finally { print 2; }");
+
+        assert_eq!(result, Err(InterpretError::Runtime));
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn break_runs_finally() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var log = \"\";
+            for (var k = 0; k < 3; k = k + 1) {
+                try {
+                    if (k == 1) break;
+                } finally {
+                    log = log + \"fin \";
+                }
+            }",
+        )?;
+
+        assert_eq!(global(&mut vm, "log").as_str(), "fin fin ");
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn continue_runs_finally() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var log = \"\";
+            for (var k = 0; k < 3; k = k + 1) {
+                var skip = k == 1;
+                try {
+                    try {
+                        if (skip) continue;
+                    } finally {
+                        log = log + \"inner \";
+                    }
+                    log = log + \"body \";
+                } catch (e) {
+                } finally {
+                    log = log + \"outer \";
+                }
+            }",
+        )?;
+
+        assert_eq!(
+            global(&mut vm, "log").as_str(),
+            "inner body outer inner outer inner body outer "
+        );
+        assert!(vm.stack.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn return_runs_finally() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var log = \"\";
+            fun f(a) {
+                try {
+                    var b = 2;
+                    fun add() { return a + b; }
+                    return add;
+                } catch (e) {
+                } finally {
+                    log = log + \"fin\";
+                }
+            }
+            var result = f(1)();",
+        )?;
+
+        assert_eq!(global(&mut vm, "log").as_str(), "fin");
+        assert!(matches!(global(&mut vm, "result"), Value::Int(3)));
+        assert!(vm.stack.is_empty());
+        assert!(vm.open_upvalues.is_empty());
+
+        Ok(())
+    }
+
+    // Writes `files` to a fresh directory and returns the path of the first
+    fn write_modules(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("rox-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = directory.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        directory.join(files[0].0)
+    }
+
+    fn run_module(vm: &mut VM, path: &Path) -> Result<Value, InterpretError> {
+        let source = fs::read_to_string(path).unwrap();
+        vm.interpret_file(path, &source)
+    }
+
+    #[test]
+    fn imports() -> Result<(), InterpretError> {
+        let main = write_modules(
+            "imports",
+            &[
+                (
+                    "main.rox",
+                    "import \"lib/counter.rox\" as counter;
+                    import \"lib/counter.rox\" for next, step;
+                    var count = 100;
+                    var a = counter.next();
+                    var b = next();
+                    var loads = counter.loads;",
+                ),
+                (
+                    "lib/counter.rox",
+                    "import \"step.rox\" for step;
+                    var count = 0;
+                    var loads = 1;
+                    fun next() { count = count + step; return count; }",
+                ),
+                ("lib/step.rox", "var step = 2;"),
+            ],
+        );
+
+        let mut vm = VM::new();
+        run_module(&mut vm, &main)?;
+
+        // The module's functions use its own globals, and it's only run once
+        assert!(matches!(global(&mut vm, "a"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "b"), Value::Int(4)));
+        assert!(matches!(global(&mut vm, "count"), Value::Int(100)));
+        assert!(matches!(global(&mut vm, "step"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "loads"), Value::Int(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_errors() {
+        let cycle = write_modules(
+            "import-cycle",
+            &[
+                ("a.rox", "import \"b.rox\" as b;"),
+                ("b.rox", "import \"a.rox\" as a;"),
+            ],
+        );
+        let missing = write_modules(
+            "import-missing",
+            &[("main.rox", "import \"missing.rox\" as m;")],
+        );
+        let undefined = write_modules(
+            "import-undefined",
+            &[
+                ("main.rox", "import \"lib.rox\" for nothing;"),
+                ("lib.rox", "var something = 1;"),
+            ],
+        );
+
+        for path in [cycle, missing, undefined] {
+            let mut vm = VM::new();
+            assert_eq!(run_module(&mut vm, &path), Err(InterpretError::Runtime));
+        }
+    }
+
+    #[test]
+    fn import_module_with_syntax_error() {
+        let main = write_modules(
+            "import-syntax-error",
+            &[
+                ("main.rox", "import \"lib.rox\" as lib;"),
+                ("lib.rox", "var = 1;"),
+            ],
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(run_module(&mut vm, &main), Err(InterpretError::Runtime));
+        let lib = fs::canonicalize(main.with_file_name("lib.rox")).unwrap();
+        assert!(!vm.modules.contains_key(&lib));
+    }
+
+    #[test]
+    fn import_after_module_throws() -> Result<(), InterpretError> {
+        let main = write_modules(
+            "import-throws",
+            &[
+                ("main.rox", "import \"lib.rox\" as lib;"),
+                ("lib.rox", "throw \"boom\";"),
+            ],
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(run_module(&mut vm, &main), Err(InterpretError::Runtime));
+
+        // Importing it again runs it again instead of finding a cycle
+        fs::write(main.with_file_name("lib.rox"), "var value = 1;").unwrap();
+        vm.interpret_source("import \"lib.rox\" for value;")?;
+        assert!(matches!(global(&mut vm, "value"), Value::Int(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_arithmetic() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var sum = 1 + 2;
+            var quotient = 7 / 2;
+            var negativeQuotient = -7 / 2;
+            var mixed = 7 / 2.0;
+            var roundTrip = 7 / 2 * 2;
+            var less = 1 < 1.5;
+            var same = 1 == 1.0;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "sum"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "quotient"), Value::Int(3)));
+        assert!(matches!(
+            global(&mut vm, "negativeQuotient"),
+            Value::Int(-3)
+        ));
+        assert!(matches!(global(&mut vm, "mixed"), Value::Float(n) if n == 3.5));
+        // Dividing two integers truncates, so the remainder is lost
+        assert!(matches!(global(&mut vm, "roundTrip"), Value::Int(6)));
+        assert_eq!(global(&mut vm, "less"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "same"), Value::Bool(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn number_conversions() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var truncated = int(-2.7);
+            var parsed = int(\" 42 \");
+            var widened = float(3);
+            var parsedFloat = float(\"0.5\");
+            var big = int(\"-100000000000000000000\");
+            var m = {1: \"one\", 100000000000000000000: \"big\"};
+            var byFloat = m[1.0];
+            var bigByFloat = m[100000000000000000000.0];",
+        )?;
+
+        assert!(matches!(global(&mut vm, "truncated"), Value::Int(-2)));
+        assert!(matches!(global(&mut vm, "parsed"), Value::Int(42)));
+        assert!(matches!(global(&mut vm, "widened"), Value::Float(n) if n == 3.0));
+        assert!(matches!(global(&mut vm, "parsedFloat"), Value::Float(n) if n == 0.5));
+        assert_eq!(global(&mut vm, "big").to_string(), "-100000000000000000000");
+        assert_eq!(global(&mut vm, "byFloat").as_str(), "one");
+        assert_eq!(global(&mut vm, "bigByFloat").as_str(), "big");
+
+        for source in [
+            "int(\"x\");",
+            "int(1.0 / 0.0);",
+            "int(nil);",
+            "float(\"x\");",
+        ] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_overflow_promotes() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var sum = 9223372036854775807 + 1;
+            var difference = -9223372036854775807 - 2;
+            var product = 4294967296 * 4294967296;
+            var negated = -(-9223372036854775807 - 1);
+            var quotient = (-9223372036854775807 - 1) / -1;
+            var literal = 100000000000000000000;
+            var back = literal - 99999999999999999999;
+            var bigger = literal > 9223372036854775807;
+            var same = literal == 100000000000000000000.0;",
+        )?;
+
+        for (name, expected) in [
+            ("sum", "9223372036854775808"),
+            ("difference", "-9223372036854775809"),
+            ("product", "18446744073709551616"),
+            ("negated", "9223372036854775808"),
+            ("quotient", "9223372036854775808"),
+        ] {
+            match global(&mut vm, name) {
+                Value::BigInt(n) => assert_eq!(n.to_string(), expected),
+                value => panic!("Expected a big integer, got {}", value),
+            }
+        }
+        // Results that fit are plain integers again
+        assert!(matches!(global(&mut vm, "back"), Value::Int(1)));
+        assert_eq!(global(&mut vm, "bigger"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "same"), Value::Bool(true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut vm = VM::new();
+
+        for source in ["1 / 0;", "100000000000000000000 / 0;"] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+    }
+
+    #[test]
+    fn modulo_and_exponentiation() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var remainder = -7 % 3;
+            var floatRemainder = 7.5 % 2;
+            var power = 2 ** 3 ** 2;
+            var negatedPower = -2 ** 2;
+            var reciprocal = 2 ** -1;
+            var bigPower = 2 ** 64;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "remainder"), Value::Int(-1)));
+        assert!(matches!(global(&mut vm, "floatRemainder"), Value::Float(n) if n == 1.5));
+        assert!(matches!(global(&mut vm, "power"), Value::Int(512)));
+        assert!(matches!(global(&mut vm, "negatedPower"), Value::Int(-4)));
+        assert!(matches!(global(&mut vm, "reciprocal"), Value::Float(n) if n == 0.5));
+        assert_eq!(
+            global(&mut vm, "bigPower").to_string(),
+            "18446744073709551616"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn bitwise_operators() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var both = 6 & 3;
+            var either = 6 | 3;
+            var xor = 6 ^ 3;
+            var not = ~5;
+            var left = 1 << 70;
+            var right = -9 >> 1;
+            var bigAnd = (left - 1) & 255;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "both"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "either"), Value::Int(7)));
+        assert!(matches!(global(&mut vm, "xor"), Value::Int(5)));
+        assert!(matches!(global(&mut vm, "not"), Value::Int(-6)));
+        assert_eq!(
+            global(&mut vm, "left").to_string(),
+            "1180591620717411303424"
+        );
+        assert!(matches!(global(&mut vm, "right"), Value::Int(-5)));
+        assert!(matches!(global(&mut vm, "bigAnd"), Value::Int(255)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bitwise_errors() {
+        let mut vm = VM::new();
+
+        for source in [
+            "1.5 & 1;",
+            "1 | 2.0;",
+            "~1.0;",
+            "1 << -1;",
+            "\"a\" ^ 1;",
+            "2 ** 5000000000;",
+        ] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+    }
+
+    #[test]
+    fn modulo_takes_the_sign_of_the_dividend() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var a = -7 % 3;
+            var b = 7 % -3;
+            var big = 2 ** 64 + 1;
+            var c = -big % 3;
+            var d = big % -3;
+            var e = -7.5 % 2;
+            var f = 7.5 % -2;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "a"), Value::Int(-1)));
+        assert!(matches!(global(&mut vm, "b"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "c"), Value::Int(-2)));
+        assert!(matches!(global(&mut vm, "d"), Value::Int(2)));
+        assert!(matches!(global(&mut vm, "e"), Value::Float(n) if n == -1.5));
+        assert!(matches!(global(&mut vm, "f"), Value::Float(n) if n == 1.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_integers() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+
+        for source in [
+            "2 ** 100000000;",
+            "(2 ** 64) ** 100000;",
+            "3 ** 170000;",
+            "1 << 100000000;",
+            "(2 ** 100000) << 200000;",
+            "var x = 2 ** 132000; x * x;",
+        ] {
+            assert_eq!(vm.interpret_source(source), Err(InterpretError::Runtime));
+        }
+
+        vm.interpret_source(
+            "var one = 1 ** 5000000000;
+            var zero = 0 ** 100000000000000000000;
+            var minusOne = (-1) ** 100000000000000000001;
+            var plusOne = (-1) ** 4000000000;
+            var shiftedZero = 0 << 4000000000;
+            var large = (2 ** 100000) << 100000;
+            var gone = 5 >> 100000000000000000000;
+            var sign = -5 >> 100000000000000000000;
+            var bigSign = -(2 ** 100) >> 4000000000;",
+        )?;
+
+        assert!(matches!(global(&mut vm, "one"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "zero"), Value::Int(0)));
+        assert!(matches!(global(&mut vm, "minusOne"), Value::Int(-1)));
+        assert!(matches!(global(&mut vm, "plusOne"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "shiftedZero"), Value::Int(0)));
+        assert!(matches!(global(&mut vm, "large"), Value::BigInt(_)));
+        assert!(matches!(global(&mut vm, "gone"), Value::Int(0)));
+        assert!(matches!(global(&mut vm, "sign"), Value::Int(-1)));
+        assert!(matches!(global(&mut vm, "bigSign"), Value::Int(-1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compound_assignment() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "class Counter { init() { this.count = 1; } }
+            var counter = Counter();
+            var calls = 0;
+            fun at(i) { calls += 1; return i; }
+            var xs = [1, 2];
+            var global = 10;
+            global -= 4;
+            global /= 2;
+            counter.count *= 5;
+            xs[at(1)] += 3;
+            fun closure() {
+                var local = 1;
+                fun add(n) { local += n; }
+                add(2);
+                return local;
+            }
+            var captured = closure();
+            var item = xs[1];",
+        )?;
+
+        assert!(matches!(global(&mut vm, "global"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "item"), Value::Int(5)));
+        assert!(matches!(global(&mut vm, "captured"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "calls"), Value::Int(1)));
+        match global(&mut vm, "counter") {
+            Value::Instance(counter) => {
+                let count = vm.heap.intern("count");
+                assert!(matches!(counter.fields.borrow()[&count], Value::Int(5)));
+            }
+            value => panic!("Expected an instance, got {}", value),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn increment_and_decrement() -> Result<(), InterpretError> {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var i = 1;
+            var post = i++;
+            var pre = ++i;
+            var m = {\"k\": 5};
+            var postIndex = m[\"k\"]--;
+            var preIndex = --m[\"k\"];",
+        )?;
+
+        assert!(matches!(global(&mut vm, "i"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "post"), Value::Int(1)));
+        assert!(matches!(global(&mut vm, "pre"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "postIndex"), Value::Int(5)));
+        assert!(matches!(global(&mut vm, "preIndex"), Value::Int(3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_expressions() -> Result<(), InterpretError> {
         let mut vm = VM::new();
-        let result = vm.run(&chunk)?;
+        vm.interpret_source(
+            "var evaluated = 0;
+            fun touch(value) { evaluated += 1; return value; }
+            var picked = 1 > 2 ? touch(\"then\") : touch(\"else\");
+            fun sign(n) { return n < 0 ? -1 : n == 0 ? 0 : 1; }
+            var signs = [sign(-5), sign(0), sign(5)];
+            var x;
+            var assigned = true ? x = 3 : 4;
+            var loose = nil or false ? \"yes\" : \"no\";",
+        )?;
 
-        assert_eq!(result, Value::Str(String::from("hello, world!")));
+        assert_eq!(global(&mut vm, "picked").as_str(), "else");
+        assert!(matches!(global(&mut vm, "evaluated"), Value::Int(1)));
+        assert_eq!(global(&mut vm, "signs").to_string(), "[-1, 0, 1]");
+        assert!(matches!(global(&mut vm, "x"), Value::Int(3)));
+        assert!(matches!(global(&mut vm, "assigned"), Value::Int(3)));
+        assert_eq!(global(&mut vm, "loose").as_str(), "no");
 
         Ok(())
     }